@@ -0,0 +1,102 @@
+// Lifecycle events, so observers like logging and metrics subscribe to what
+// happens instead of each being called from handle_client.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{Clock, Peer, Source};
+
+// Room for bursts; a subscriber that falls further behind skips events
+const EVENT_BUFFER: usize = 1024;
+
+#[derive(Clone)]
+pub enum Event {
+    Connected { peer: Peer },
+    Registered { name: String, source: Source },
+    // Every stored chat message: clients, the bot and the webhook
+    Message { sender: String, source: Source },
+    Retracted { sender: String },
+    Left { name: String, peer: Peer },
+}
+
+impl Event {
+    fn describe(&self) -> String {
+        match self {
+            Event::Connected { peer: Peer::Tcp(addr) } => format!("connection from {}", addr),
+            Event::Connected { peer: Peer::Unix } => "connection on the Unix socket".to_string(),
+            Event::Registered { name, source } => format!("{} joined via {}", name, source.label()),
+            Event::Message { sender, source } => format!("message from {} via {}", sender, source.label()),
+            Event::Retracted { sender } => format!("{} retracted a message", sender),
+            Event::Left { name, peer: Peer::Tcp(addr) } => format!("{} left, was connected from {}", name, addr),
+            Event::Left { name, peer: Peer::Unix } => format!("{} left the Unix socket", name),
+        }
+    }
+}
+
+// Two kinds of subscriber: best-effort ones like the logger share a bounded
+// broadcast and may skip events, while ones that must count every event,
+// like the metrics, each get an unbounded queue
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+    every: Mutex<Vec<mpsc::UnboundedSender<Event>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus { tx: broadcast::channel(EVENT_BUFFER).0, every: Mutex::new(Vec::new()) }
+    }
+
+    // Nobody listening is fine; events are for observers, not for delivery.
+    // A subscriber that has gone away is dropped from the list.
+    pub fn emit(&self, event: Event) {
+        self.every.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    pub fn subscribe_to_every(&self) -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.every.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+// Totals since the server started, shown by /stats; kept by record_metrics
+#[derive(Default)]
+pub struct Metrics {
+    pub connections: AtomicU64,
+    pub messages: AtomicU64,
+}
+
+impl Metrics {
+    fn record(&self, event: &Event) {
+        let counter = match event {
+            Event::Connected { .. } => &self.connections,
+            Event::Message { .. } => &self.messages,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn record_metrics(mut rx: mpsc::UnboundedReceiver<Event>, metrics: Arc<Metrics>) {
+    while let Some(event) = rx.recv().await {
+        metrics.record(&event);
+    }
+}
+
+// One stdout line per event, for --log
+pub async fn log(mut rx: broadcast::Receiver<Event>, clock: Arc<dyn Clock>) {
+    loop {
+        let line = match rx.recv().await {
+            Ok(event) => event.describe(),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => format!("log fell behind, skipped {} events", skipped),
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        println!("[{}] {}", clock.now().format("%Y-%m-%d %H:%M:%S"), line);
+    }
+}
@@ -5,21 +5,287 @@
 // tokio = { version = "1", features = ["full"] }
 // uuid = { version = "1" }
 // chrono = "0.4"
+// serde = { version = "1", features = ["derive"] }
+// serde_json = "1"
+// dashmap = "6"
+// lru = "0.12"
+// ipnet = "2"
+// rusqlite = { version = "0.32", features = ["bundled"] }
+// --- Dev dependencies, for the tests' paused clock ---
+// tokio = { version = "1", features = ["full", "test-util"] }
 
-use std::collections::HashMap;
-use std::io::{self, BufRead, Write};
+mod events;
+mod poll;
+mod store;
+mod webhook;
+
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::num::NonZeroUsize;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use dashmap::DashMap;
+use ipnet::IpNet;
+use lru::LruCache;
 use uuid::Uuid;
-use chrono::Local;
+use chrono::{DateTime, Local};
+use events::{Event, EventBus, Metrics};
+use poll::{parse_poll_args, PollBook, VOTE_USAGE};
+use store::{MemoryStore, MessageStore, SearchQuery, SqliteStore};
+
+// Server settings, taken from command-line flags
+struct Config {
+    // Networks allowed to connect; empty accepts everyone
+    allow: Vec<IpNet>,
+    max_connections_per_minute: usize,
+    max_handlers: usize,
+    // Messages of the day, handed out round-robin to new connections
+    motds: Vec<String>,
+    // Art shown on connect, before the name prompt; ends with a newline
+    banner: Option<String>,
+    // End output lines with \r\n instead of \n
+    crlf: bool,
+    // Start every connection with debug echo on
+    echo: bool,
+    // Layout of stored chat lines, as broadcast and in search results
+    format: Template,
+    // Write a line to stdout for each lifecycle event
+    log: bool,
+    // Longest chat message kept, in characters; longer ones are cut
+    max_message_len: usize,
+    // Disconnect after this many unknown commands in a row; 0 never does
+    max_unknown_commands: usize,
+    // How long a new connection has to send its name, HELLO included
+    name_timeout: Duration,
+    // None disables the prompt for programmatic clients
+    prompt: Option<String>,
+    // Listen on this Unix socket path instead of TCP
+    unix: Option<String>,
+    // Keep history in this SQLite file instead of memory
+    db: Option<String>,
+    webhook_bind: Option<String>,
+    webhook_secret: Option<String>,
+    // (trigger, reply) pairs loaded from --bot-file; empty means the bot is off
+    bot_triggers: Vec<(String, String)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            allow: Vec::new(),
+            max_connections_per_minute: 20,
+            max_handlers: 256,
+            motds: Vec::new(),
+            banner: None,
+            crlf: false,
+            echo: false,
+            format: Template::default(),
+            log: false,
+            max_message_len: 1000,
+            max_unknown_commands: 10,
+            name_timeout: Duration::from_secs(30),
+            prompt: Some("> ".to_string()),
+            unix: None,
+            db: None,
+            webhook_bind: None,
+            webhook_secret: None,
+            bot_triggers: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    fn from_args() -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--allow" => {
+                    let value: String = parse_value(&arg, args.next())?;
+                    // A bare address is shorthand for a single-host network
+                    let net = value.parse::<IpNet>()
+                        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+                        .map_err(|_| format!("invalid value for --allow: {}", value))?;
+                    config.allow.push(net);
+                }
+                "--max-conn-per-min" => {
+                    config.max_connections_per_minute = parse_value(&arg, args.next())?;
+                }
+                "--max-handlers" => config.max_handlers = parse_value(&arg, args.next())?,
+                "--max-message-len" => config.max_message_len = parse_value(&arg, args.next())?,
+                "--max-unknown-commands" => config.max_unknown_commands = parse_value(&arg, args.next())?,
+                "--motd" => config.motds = vec![parse_value(&arg, args.next())?],
+                "--motd-file" => {
+                    let path: String = parse_value(&arg, args.next())?;
+                    config.motds = load_motds(&path)?;
+                }
+                "--banner" => {
+                    let path: String = parse_value(&arg, args.next())?;
+                    config.banner = load_banner(&path);
+                }
+                "--crlf" => config.crlf = true,
+                "--echo" => config.echo = true,
+                "--log" => config.log = true,
+                "--name-timeout" => config.name_timeout = Duration::from_secs(parse_value(&arg, args.next())?),
+                "--format" => config.format = Template::parse(&parse_value::<String>(&arg, args.next())?)?,
+                "--prompt" => config.prompt = Some(parse_value(&arg, args.next())?),
+                "--no-prompt" => config.prompt = None,
+                "--unix" => config.unix = Some(parse_value(&arg, args.next())?),
+                "--db" => config.db = Some(parse_value(&arg, args.next())?),
+                "--webhook-bind" => config.webhook_bind = Some(parse_value(&arg, args.next())?),
+                "--webhook-secret" => config.webhook_secret = Some(parse_value(&arg, args.next())?),
+                "--bot-file" => {
+                    let path: String = parse_value(&arg, args.next())?;
+                    config.bot_triggers = load_bot_triggers(&path)?;
+                }
+                _ => return Err(format!("unknown option: {}", arg)),
+            }
+        }
+        if config.webhook_bind.is_some() && config.webhook_secret.as_deref().is_none_or(str::is_empty) {
+            return Err("--webhook-bind requires a non-empty --webhook-secret".to_string());
+        }
+        if config.unix.is_some() && !cfg!(unix) {
+            return Err("--unix is only supported on Unix platforms".to_string());
+        }
+        if config.max_message_len == 0 {
+            return Err("--max-message-len must be at least 1".to_string());
+        }
+        if config.name_timeout.is_zero() {
+            return Err("--name-timeout must be at least 1 second".to_string());
+        }
+        if config.max_handlers == 0 {
+            return Err("--max-handlers must be at least 1".to_string());
+        }
+        if config.max_connections_per_minute == 0 {
+            return Err("--max-conn-per-min must be at least 1".to_string());
+        }
+        Ok(config)
+    }
+}
+
+// Bot files hold one `trigger = reply` per line; blank lines and `#` comments are skipped
+fn load_bot_triggers(path: &str) -> Result<Vec<(String, String)>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let mut triggers = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((trigger, reply)) if !trigger.trim().is_empty() && !reply.trim().is_empty() => {
+                triggers.push((trigger.trim().to_lowercase(), reply.trim().to_string()));
+            }
+            _ => return Err(format!("{}:{}: expected `trigger = reply`", path, number + 1)),
+        }
+    }
+    Ok(triggers)
+}
+
+// MOTD files hold one message per block, with blocks separated by blank lines
+fn load_motds(path: &str) -> Result<Vec<String>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let mut motds = Vec::new();
+    let mut block: Vec<&str> = Vec::new();
+    for line in text.lines().chain(std::iter::once("")) {
+        if line.trim().is_empty() {
+            if !block.is_empty() {
+                motds.push(block.join("\n"));
+                block.clear();
+            }
+        } else {
+            block.push(line.trim_end());
+        }
+    }
+    Ok(motds)
+}
+
+const MAX_BANNER_LINES: usize = 40;
+const MAX_BANNER_WIDTH: usize = 100;
+
+// Cosmetic, so a missing or empty banner file only warns. The banner is cut to
+// MAX_BANNER_LINES lines of MAX_BANNER_WIDTH characters, since every connection gets a copy.
+fn load_banner(path: &str) -> Option<String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("warning: no banner, cannot read {}: {}", path, e);
+            return None;
+        }
+    };
+    let mut banner = String::new();
+    for line in text.lines().take(MAX_BANNER_LINES) {
+        banner.push_str(truncate_chars(line.trim_end(), MAX_BANNER_WIDTH));
+        banner.push('\n');
+    }
+    if banner.trim().is_empty() {
+        eprintln!("warning: no banner, {} is empty", path);
+        return None;
+    }
+    Some(banner)
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+// Which entry point a user or message came in through
+#[derive(Clone, Copy, PartialEq)]
+enum Source {
+    Tcp,
+    Unix,
+    Webhook,
+    Bot,
+}
+
+impl Source {
+    fn label(self) -> &'static str {
+        match self {
+            Source::Tcp => "TCP",
+            Source::Unix => "Unix socket",
+            Source::Webhook => "webhook",
+            Source::Bot => "bot",
+        }
+    }
+}
+
+// Keys `users`, so a connection is identified the same way whatever transport it came in on
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct ConnectionId(u64);
+
+// Where a connection came from; Unix socket peers have no useful address
+#[derive(Clone, Copy)]
+enum Peer {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl Peer {
+    fn source(self) -> Source {
+        match self {
+            Peer::Tcp(_) => Source::Tcp,
+            Peer::Unix => Source::Unix,
+        }
+    }
+}
 
 #[derive(Clone)]
 struct User {
     id: String,
     name: String,
+    connected_at: DateTime<Local>,
+    source: Source,
+    peer: Peer,
 }
 
 #[derive(Clone)]
@@ -27,183 +293,2209 @@ struct Message {
     sender_id: String,
     content: String,
     timestamp: String,
+    // When it was sent in Unix seconds, for comparing times; `timestamp` is
+    // local time for people, and repeats an hour when daylight saving ends
+    sent_at: i64,
+    source: Source,
+    // Id of the registration that sent it, so only that connection can /undo
+    // it; None for the bot and the webhook
+    author: Option<String>,
+    // When the message is deleted, in Unix seconds, from the sender's
+    // /retention when they sent it. Not local time, which repeats an hour when
+    // daylight saving ends.
+    expires_at: Option<i64>,
 }
 
 impl Message {
-    fn format(&self) -> String {
-        format!("[{}] {}: {}", self.timestamp, self.sender_id, self.content)
+    fn format(&self, template: &Template) -> String {
+        let mut line = String::new();
+        for part in &template.parts {
+            match part {
+                Part::Text(text) => line.push_str(text),
+                Part::Timestamp => line.push_str(&self.timestamp),
+                Part::Name => line.push_str(&self.sender_id),
+                Part::Content => line.push_str(&self.content),
+            }
+        }
+        line
+    }
+
+    // `@name` with the exact name, not as the start of a longer one (`@al` doesn't mention `alice`)
+    fn mentions(&self, name: &str) -> bool {
+        let tag = format!("@{}", name);
+        self.content.match_indices(&tag).any(|(at, _)| {
+            let before = self.content[..at].chars().next_back();
+            let after = self.content[at + tag.len()..].chars().next();
+            !before.is_some_and(is_name_char) && !after.is_some_and(is_name_char)
+        })
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+const MAX_NAME_LEN: usize = 24;
+
+// Names are only name characters, so they can't look like a command, hold
+// spaces that split arguments, or contain the @ that marks a mention. The
+// bot's name is reserved so nobody can speak as it.
+fn check_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() {
+        return Err("A name can't be empty.");
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err("A name can be at most 24 characters.");
+    }
+    if !name.chars().all(is_name_char) {
+        return Err("A name can only use letters, digits, '_' and '-'.");
+    }
+    if name.eq_ignore_ascii_case(BOT_NAME) {
+        return Err("That name is reserved for the bot.");
+    }
+    Ok(())
+}
+
+const DEFAULT_FORMAT: &str = "[{ts}] {name}: {content}";
+
+// How chat lines are laid out, from --format; `{{` and `}}` are literal braces
+struct Template {
+    parts: Vec<Part>,
+}
+
+enum Part {
+    Text(String),
+    Timestamp,
+    Name,
+    Content,
+}
+
+impl Template {
+    fn parse(text: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if let Some(after) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+                literal.push(c);
+                rest = after;
+                continue;
+            }
+            if c != '{' {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+                continue;
+            }
+            let (name, after) = rest[1..].split_once('}')
+                .ok_or_else(|| format!("invalid --format: unclosed '{{' in {}", text))?;
+            let part = match name {
+                "ts" => Part::Timestamp,
+                "name" => Part::Name,
+                "content" => Part::Content,
+                "room" => return Err("invalid --format: {room} isn't supported, the server has no rooms".to_string()),
+                _ => return Err(format!("invalid --format: unknown placeholder {{{}}}; use {{ts}}, {{name}} and {{content}}", name)),
+            };
+            if !literal.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut literal)));
+            }
+            parts.push(part);
+            rest = after;
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+        // Without the content every message would render the same
+        if !parts.iter().any(|part| matches!(part, Part::Content)) {
+            return Err("invalid --format: it must include {content}".to_string());
+        }
+        Ok(Template { parts })
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template::parse(DEFAULT_FORMAT).unwrap()
+    }
+}
+
+// Which part of a message a search looks at
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum SearchField {
+    Content,
+    Sender,
+    Both,
+}
+
+impl SearchField {
+    fn label(self) -> &'static str {
+        match self {
+            SearchField::Content => "keyword",
+            SearchField::Sender => "user",
+            SearchField::Both => "keyword or user",
+        }
+    }
+}
+
+// Which end of history search results start from
+#[derive(Clone, Copy)]
+enum SearchOrder {
+    Oldest,
+    Newest,
+    // Best match first, from the full-text index
+    Relevant,
+}
+
+// Bumped whenever the line protocol changes in a way clients need to know about
+const PROTOCOL_VERSION: u32 = 1;
+
+const SEARCH_CACHE_SIZE: usize = 64;
+
+const BOT_NAME: &str = "Bot";
+const BOT_COOLDOWN: Duration = Duration::from_secs(30);
+
+// Results are saved with the store's generation at the time of the search
+type SearchCache = LruCache<(SearchField, String), (u64, Vec<String>)>;
+
+// Where ChatManager and the handlers get the time, so a fixed or advancing
+// clock can stand in for the real one. Timers the handler sleeps on, like
+// the name timeout, use tokio's clock instead, which tests can pause.
+trait Clock: Send + Sync {
+    // Wall-clock time, for timestamps people read
+    fn now(&self) -> DateTime<Local>;
+    // Monotonic time, for rate windows, cooldowns and expiries
+    fn instant(&self) -> Instant;
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
     }
 
-    fn matches(&self, keyword: &str) -> bool {
-        self.content.contains(keyword) || self.sender_id.contains(keyword)
+    fn instant(&self) -> Instant {
+        Instant::now()
     }
 }
 
 struct ChatManager {
-    messages: Arc<Mutex<Vec<Message>>>,
-    users: Arc<Mutex<HashMap<SocketAddr, User>>>,
+    config: Config,
+    clock: Arc<dyn Clock>,
+    messages: Box<dyn MessageStore>,
+    // Sharded map so lookups from many handlers don't contend on one lock
+    users: Arc<DashMap<ConnectionId, User>>,
+    // Held by register_user so checking a name and taking it is one step
+    registering: Mutex<()>,
+    next_connection: AtomicU64,
+    message_counts: Arc<Mutex<HashMap<String, u64>>>,
+    // Formatted results of recent searches, stale once history has changed
+    search_cache: Arc<Mutex<SearchCache>>,
+    // Held by post so broadcasts go out in the order messages are stored
+    post_order: Mutex<()>,
+    connection_attempts: Arc<Mutex<HashMap<IpAddr, Vec<Instant>>>>,
+    // Index of the MOTD the next connection gets
+    next_motd: AtomicUsize,
+    // When each bot trigger last fired, indexed like config.bot_triggers
+    bot_last_reply: Arc<Mutex<HashMap<usize, Instant>>>,
+    polls: Arc<Mutex<PollBook>>,
+    events: EventBus,
+    metrics: Arc<Metrics>,
 }
 
 impl ChatManager {
-    fn new() -> Self {
+    fn new(config: Config, clock: Arc<dyn Clock>, messages: Box<dyn MessageStore>) -> Self {
         ChatManager {
-            messages: Arc::new(Mutex::new(Vec::new())),
-            users: Arc::new(Mutex::new(HashMap::new())),
+            config,
+            clock,
+            message_counts: Arc::new(Mutex::new(messages.sender_counts())),
+            messages,
+            users: Arc::new(DashMap::new()),
+            registering: Mutex::new(()),
+            next_connection: AtomicU64::new(1),
+            search_cache: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(SEARCH_CACHE_SIZE).unwrap()))),
+            post_order: Mutex::new(()),
+            connection_attempts: Arc::new(Mutex::new(HashMap::new())),
+            next_motd: AtomicUsize::new(0),
+            bot_last_reply: Arc::new(Mutex::new(HashMap::new())),
+            polls: Arc::new(Mutex::new(PollBook::default())),
+            events: EventBus::new(),
+            metrics: Arc::new(Metrics::default()),
         }
     }
 
-    fn store_message(&self, msg: Message) {
-        self.messages.lock().unwrap().push(msg);
+    // Answer a `HELLO <version>` handshake with the version we'll speak and what's switched on
+    fn hello(&self, version: &str) -> String {
+        let Ok(version) = version.parse::<u32>() else {
+            return format!("ERROR invalid protocol version, speaking {}\n", PROTOCOL_VERSION);
+        };
+        format!("HELLO {} features={}\n", version.min(PROTOCOL_VERSION), self.features().join(","))
+    }
+
+    // Optional features switched on by flags, as named in the HELLO reply
+    fn features(&self) -> Vec<&'static str> {
+        let mut features = Vec::new();
+        if self.config.prompt.is_some() {
+            features.push("prompt");
+        }
+        if !self.config.motds.is_empty() {
+            features.push("motd");
+        }
+        if !self.config.bot_triggers.is_empty() {
+            features.push("bot");
+        }
+        if self.config.webhook_bind.is_some() {
+            features.push("webhook");
+        }
+        features
     }
 
-    fn search_messages(&self, query: &str) -> Vec<String> {
-        self.messages.lock().unwrap().iter()
-            .filter(|msg| msg.matches(query))
-            .map(|m| m.format())
-            .collect()
+    // What this server supports, for /caps; one `key: value` per line so clients can parse it
+    fn caps(&self) -> Vec<String> {
+        let history = match self.config.db {
+            Some(_) => "sqlite, full-text search",
+            None => "memory",
+        };
+        vec![
+            format!("protocol: {}", PROTOCOL_VERSION),
+            format!("commands: {}", COMMANDS.join(" ")),
+            format!("features: {}", self.features().join(",")),
+            format!("history: {}", history),
+            format!("max-message-len: {}", self.config.max_message_len),
+            format!("max-unknown-commands: {}", self.config.max_unknown_commands),
+            format!("max-conn-per-min: {}", self.config.max_connections_per_minute),
+            "unsupported: rooms,dms,tls".to_string(),
+        ]
+    }
+
+    // Rotate through the configured MOTDs, one per connection
+    fn pick_motd(&self) -> Option<String> {
+        if self.config.motds.is_empty() {
+            return None;
+        }
+        let index = self.next_motd.fetch_add(1, Ordering::Relaxed) % self.config.motds.len();
+        Some(self.config.motds[index].clone())
+    }
+
+    // Message timestamp for the current moment
+    fn timestamp(&self) -> String {
+        self.clock.now().format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+
+    // The current moment in Unix seconds, for a message's sent_at
+    fn sent_at(&self) -> i64 {
+        self.clock.now().timestamp()
+    }
+
+    // When a message sent now under `/retention <hours>` expires
+    fn expires_at(&self, hours: i64) -> i64 {
+        (self.clock.now() + chrono::Duration::hours(hours)).timestamp()
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.config.allow.is_empty() || self.config.allow.iter().any(|net| net.contains(&ip))
+    }
+
+    // Record a connection attempt, refusing IPs that opened too many in the last minute
+    fn allow_connection(&self, ip: IpAddr) -> bool {
+        let now = self.clock.instant();
+        let window = Duration::from_secs(60);
+        let mut attempts = self.connection_attempts.lock().unwrap();
+        attempts.retain(|_, times| {
+            times.retain(|t| now.duration_since(*t) < window);
+            !times.is_empty()
+        });
+        let times = attempts.entry(ip).or_default();
+        if times.len() >= self.config.max_connections_per_minute {
+            return false;
+        }
+        times.push(now);
+        true
+    }
+
+    // Store a chat message and broadcast it. Both happen under post_order,
+    // so concurrent posts reach every client in the order they are stored.
+    // With no receivers there is nobody to tell, so a failed send is fine.
+    // Returns the line as broadcast.
+    fn post(&self, msg: Message, tx: &broadcast::Sender<String>) -> String {
+        *self.message_counts.lock().unwrap().entry(msg.sender_id.clone()).or_insert(0) += 1;
+        let line = msg.format(&self.config.format);
+        self.events.emit(Event::Message { sender: msg.sender_id.clone(), source: msg.source });
+        let _order = self.post_order.lock().unwrap();
+        self.messages.store(msg);
+        let _ = tx.send(line.clone());
+        line
+    }
+
+    // Serve a search from the cache, or run it and remember the result. The
+    // generation is read before searching, so a change that lands mid-search
+    // leaves the saved result stale rather than looking current. The cache
+    // lock is only held to look up and save, never across the search.
+    fn cached_search(&self, field: SearchField, query: &str) -> Vec<String> {
+        let key = (field, query.to_string());
+        let generation = self.messages.generation();
+        if let Some((saved, results)) = self.search_cache.lock().unwrap().get(&key)
+            && *saved == generation
+        {
+            return results.clone();
+        }
+        // Content searches use the store's full-text index when it has one
+        let found = match field {
+            SearchField::Content => self.messages.full_text_search(query, false),
+            _ => None,
+        };
+        let found = found.unwrap_or_else(|| self.messages.search(&SearchQuery::new(query, field)));
+        let results: Vec<String> = found.iter().map(|msg| msg.format(&self.config.format)).collect();
+        self.search_cache.lock().unwrap().put(key, (generation, results.clone()));
+        results
+    }
+
+    // Canned reply for the first trigger in the message, unless that trigger fired recently
+    fn bot_reply(&self, content: &str) -> Option<Message> {
+        let content = content.to_lowercase();
+        let index = self.config.bot_triggers.iter()
+            .position(|(trigger, _)| content.contains(trigger.as_str()))?;
+        let now = self.clock.instant();
+        let mut last_reply = self.bot_last_reply.lock().unwrap();
+        if last_reply.get(&index).is_some_and(|t| now.duration_since(*t) < BOT_COOLDOWN) {
+            return None;
+        }
+        last_reply.insert(index, now);
+        Some(Message {
+            sender_id: BOT_NAME.to_string(),
+            content: self.config.bot_triggers[index].1.clone(),
+            timestamp: self.timestamp(),
+            sent_at: self.sent_at(),
+            source: Source::Bot,
+            author: None,
+            expires_at: None,
+        })
+    }
+
+    fn message_count(&self, name: &str) -> u64 {
+        self.message_counts.lock().unwrap().get(name).copied().unwrap_or(0)
+    }
+
+    // Top talkers by message count, ties broken alphabetically
+    fn leaderboard(&self, n: usize) -> Vec<(String, u64)> {
+        let mut counts: Vec<(String, u64)> = self.message_counts.lock().unwrap().iter()
+            .map(|(name, count)| (name.clone(), *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(n);
+        counts
+    }
+
+    // Activity report for the last `minutes`, based on when messages were sent
+    fn summary(&self, minutes: i64) -> Vec<String> {
+        let since = self.sent_at() - minutes * 60;
+        let query = SearchQuery { since: Some(since), ..SearchQuery::new("", SearchField::Both) };
+        let mut per_user: HashMap<String, u64> = HashMap::new();
+        let mut total = 0;
+        for msg in self.messages.search(&query) {
+            total += 1;
+            *per_user.entry(msg.sender_id).or_insert(0) += 1;
+        }
+        if total == 0 {
+            return vec![format!("No activity in the last {} minutes.", minutes)];
+        }
+
+        let mut active: Vec<(String, u64)> = per_user.into_iter().collect();
+        active.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let top: Vec<String> = active.iter().take(3)
+            .map(|(name, count)| format!("{} ({})", name, count))
+            .collect();
+        vec![
+            format!("Last {} minutes: {} messages from {} users", minutes, total, active.len()),
+            format!("Most active: {}", top.join(", ")),
+        ]
+    }
+
+    fn stats(&self, requester: &str) -> Vec<String> {
+        vec![
+            format!("Users online: {}", self.users.len()),
+            format!("Messages stored: {}", self.messages.len()),
+            format!("Your messages: {}", self.message_count(requester)),
+            format!("Connections since start: {}", self.metrics.connections.load(Ordering::Relaxed)),
+            format!("Messages since start: {}", self.metrics.messages.load(Ordering::Relaxed)),
+        ]
+    }
+
+    fn next_connection_id(&self) -> ConnectionId {
+        ConnectionId(self.next_connection.fetch_add(1, Ordering::Relaxed))
     }
 
-    fn register_user(&self, addr: SocketAddr, name: String) -> User {
+    // Err if someone online already has the name. The check and the insert
+    // happen under one lock, so two clients racing for a name can't both get it.
+    fn register_user(&self, connection: ConnectionId, peer: Peer, name: String) -> Result<User, String> {
+        let _registering = self.registering.lock().unwrap();
+        if self.users.iter().any(|u| u.name == name) {
+            return Err(format!("The name {} is taken, pick another.", name));
+        }
         let user = User {
             id: Uuid::new_v4().to_string(),
-            name: name.clone(),
+            name,
+            connected_at: self.clock.now(),
+            source: peer.source(),
+            peer,
         };
-        self.users.lock().unwrap().insert(addr, user.clone());
-        user
+        self.users.insert(connection, user.clone());
+        self.events.emit(Event::Registered { name: user.name.clone(), source: user.source });
+        Ok(user)
     }
 
-    fn remove_user(&self, addr: &SocketAddr) {
-        self.users.lock().unwrap().remove(addr);
+    fn remove_user(&self, connection: ConnectionId) {
+        if let Some((_, user)) = self.users.remove(&connection) {
+            self.events.emit(Event::Left { name: user.name, peer: user.peer });
+        }
     }
-    
-    // Search messages by user name
-    fn search_messages_by_user(&self, user_name: &str) -> Vec<String> {
-        self.messages.lock().unwrap().iter()
-            .filter(|msg| msg.sender_id.contains(user_name))
-            .map(|m| m.format())
-            .collect()
+
+    fn last_source(&self, name: &str) -> Option<Source> {
+        self.messages.search(&SearchQuery::new(name, SearchField::Sender)).iter().rev()
+            .find(|msg| msg.sender_id == name)
+            .map(|msg| msg.source)
+    }
+
+    fn find_user(&self, name: &str) -> Option<User> {
+        self.users.iter().find(|u| u.name == name).map(|u| u.value().clone())
+    }
+
+    // Open a poll and return the announcement to broadcast
+    fn open_poll(&self, creator: &str, question: String, options: Vec<String>, duration: Option<Duration>) -> Result<String, &'static str> {
+        let mut polls = self.polls.lock().unwrap();
+        let now = self.clock.instant();
+        polls.open(question, options, duration, now).map(|poll| poll.announcement(creator, now))
+    }
+
+    fn vote(&self, id: u64, voter: &str, choice: &str) -> Result<String, String> {
+        self.polls.lock().unwrap().vote(id, voter, choice, self.clock.instant())
+    }
+
+    fn poll_results(&self, id: u64) -> Option<Vec<String>> {
+        self.polls.lock().unwrap().results(id, self.clock.instant())
+    }
+
+    // Describe a user for /whois; offline users are known only through their history
+    fn whois(&self, name: &str) -> Vec<String> {
+        let message_count = self.message_count(name);
+        match self.find_user(name) {
+            Some(user) => vec![
+                format!("{} is online", user.name),
+                format!("  connected since: {} via {}", user.connected_at.format("%Y-%m-%d %H:%M:%S"), user.source.label()),
+                format!("  messages sent: {}", message_count),
+            ],
+            None if message_count > 0 => {
+                let mut details = vec![
+                    format!("{} is offline", name),
+                    format!("  messages sent: {}", message_count),
+                ];
+                if let Some(source) = self.last_source(name) {
+                    details.push(format!("  last posted via {}", source.label()));
+                }
+                details
+            }
+            None => vec![format!("No such user: {}", name)],
+        }
+    }
+
+    // Delete messages whose /retention has run out
+    fn sweep_expired(&self) {
+        let removed = self.messages.purge_expired(self.clock.now().timestamp());
+        // Counts follow what's stored, as they would after a restart
+        let mut counts = self.message_counts.lock().unwrap();
+        for (sender, removed) in removed {
+            if let Some(count) = counts.get_mut(&sender) {
+                *count = count.saturating_sub(removed);
+            }
+        }
+    }
+
+    // Take back the latest message this registration sent if it is under
+    // UNDO_WINDOW old, and tell everyone. Matching on the id rather than the
+    // name means someone reconnecting under a name can't undo what an earlier
+    // holder of it sent. The message went out when it was posted, so the
+    // notice follows it without taking post_order; the removal waits on the
+    // store, so handlers call this off the async workers.
+    fn undo(&self, user: &User, tx: &broadcast::Sender<String>) -> Option<Message> {
+        let since = self.sent_at() - UNDO_WINDOW.as_secs() as i64;
+        let removed = self.messages.remove_latest(&user.id, since)?;
+        let name = &user.name;
+        if let Some(count) = self.message_counts.lock().unwrap().get_mut(name) {
+            *count = count.saturating_sub(1);
+        }
+        let _ = tx.send(format!("*** {} retracted their message from {} ***", name, removed.timestamp));
+        self.events.emit(Event::Retracted { sender: name.to_string() });
+        Some(removed)
+    }
+
+    // The last `count` stored messages, oldest first, for /resync
+    fn recent(&self, count: usize) -> Vec<String> {
+        let mut lines: Vec<String> = self.messages.recent(0, count).iter()
+            .map(|msg| msg.format(&self.config.format))
+            .collect();
+        lines.reverse();
+        lines
+    }
+
+    // Describe the requesting connection for /whoami. There are no rooms or
+    // away states yet, so everyone is in the one chat and online while connected.
+    fn whoami(&self, user: &User) -> Vec<String> {
+        vec![
+            format!("You are {}", user.name),
+            format!("  id: {}", user.id),
+            format!("  connected since: {} via {}", user.connected_at.format("%Y-%m-%d %H:%M:%S"), user.source.label()),
+            format!("  messages sent: {}", self.message_count(&user.name)),
+        ]
+    }
+
+    // Search message content, sender names, or both
+    fn search_messages(&self, query: &str, field: SearchField, order: SearchOrder) -> Result<Vec<String>, &'static str> {
+        let mut results = match order {
+            // Ranked results depend on the query, so they skip the cache
+            SearchOrder::Relevant => {
+                let ranked = match field {
+                    SearchField::Content => self.messages.full_text_search(query, true),
+                    _ => None,
+                };
+                let ranked = ranked.ok_or("'--order relevant' needs --db, and only searches message content.")?;
+                return Ok(ranked.iter().map(|msg| msg.format(&self.config.format)).collect());
+            }
+            SearchOrder::Oldest | SearchOrder::Newest => self.cached_search(field, query),
+        };
+        // History is stored oldest first, so newest-first is just the reverse
+        if let SearchOrder::Newest = order {
+            results.reverse();
+        }
+        Ok(results)
     }
 
-    // Search messages by keyword (content or sender name)
-    fn search_messages_by_keyword(&self, keyword: &str) -> Vec<String> {
-        self.messages.lock().unwrap().iter()
-            .filter(|msg| msg.content.contains(keyword))
-            .map(|m| m.format())
+    // Newest messages that @mention `name`, at most `count` of them
+    fn mentions(&self, name: &str, count: usize) -> Vec<String> {
+        let tag = format!("@{}", name);
+        self.messages.search(&SearchQuery::new(&tag, SearchField::Content)).iter().rev()
+            .filter(|msg| msg.mentions(name))
+            .take(count)
+            .map(|msg| msg.format(&self.config.format))
             .collect()
-    }    
+    }
+
+    // Count messages whose content mentions the keyword, ignoring case
+    fn count_messages_by_keyword(&self, keyword: &str) -> usize {
+        let query = SearchQuery { ignore_case: true, ..SearchQuery::new(keyword, SearchField::Content) };
+        self.messages.search(&query).len()
+    }
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    let (tx, _rx) = broadcast::channel(100);
-    let chat_manager = Arc::new(ChatManager::new());
+const SEARCH_USAGE: &str = "Usage: /search [--in content|sender|both] [--order newest|oldest|relevant] <query>";
 
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        let tx = tx.clone();
-        let mut rx = tx.subscribe();
-        let chat_manager = chat_manager.clone();
-
-        tokio::spawn(async move {
-            let (reader, mut writer) = socket.into_split();
-            let mut reader = BufReader::new(reader);
-            let mut line = String::new();
-
-            // Get user's name
-            writer.write_all(b"Enter your name: ").await.unwrap();
-            writer.flush().await.unwrap();
-            reader.read_line(&mut line).await.unwrap();
-            let name = line.trim().to_string();
-            let user = chat_manager.register_user(addr, name);
-            line.clear();
-
-            // Show command instructions in a box
-            writer.write_all(b"\n\n***************************************************\n").await.unwrap();
-            writer.write_all(b"- Type 'exit' to leave\n").await.unwrap();
-            writer.write_all(b"- Type '/search <query>' to search by keyword\n").await.unwrap();
-            writer.write_all(b"- Type '/user <username>' to search by user\n").await.unwrap();
-            writer.write_all(b"- Type any other message to chat\n").await.unwrap();
-            writer.write_all(b"***************************************************\n\n\n").await.unwrap();
-            writer.flush().await.unwrap();
-
-            // Notify others of join with new format
-            let join_msg = format!("\n\n*** {} has joined at {} ***\n\n", 
-                user.name,
-                Local::now().format("%Y-%m-%d %H:%M:%S")
-            );
-            tx.send(join_msg).unwrap();
-
-            loop {
-                writer.write_all(b"> ").await.unwrap();
-                writer.flush().await.unwrap();
-                tokio::select! {
-                    result = reader.read_line(&mut line) => {
-                        if result.unwrap() == 0 { break; }
-                        let content = line.trim().to_string();
-
-                        if content == "exit" {
-                            // Notify others of leave with new format
-                            let leave_msg = format!("\n\n*** {} has left at {} ***\n\n", 
-                                user.name,
-                                Local::now().format("%Y-%m-%d %H:%M:%S")
-                            );
-                            chat_manager.remove_user(&addr);
-                            tx.send(leave_msg).unwrap();
-                            break;
-                        } else if content.starts_with("/search ") {
-                            let query = content.splitn(2, " ").nth(1).unwrap();
-                            let search_results = chat_manager.search_messages_by_keyword(query);
-                            if !search_results.is_empty() {
-                                writer.write_all(b"Search results by keyword:\n").await.unwrap();
-                                for result in search_results {
-                                    writer.write_all(result.as_bytes()).await.unwrap();
-                                    writer.write_all(b"\n").await.unwrap();
-                                }
-                            } else {
-                                writer.write_all(b"No results found.\n").await.unwrap();
-                            }
-                            writer.flush().await.unwrap();
-                        } else if content.starts_with("/user ") {
-                            let query = content.splitn(2, " ").nth(1).unwrap();
-                            let search_results = chat_manager.search_messages_by_user(query);
-                            if !search_results.is_empty() {
-                                writer.write_all(b"Search results by user:\n").await.unwrap();
-                                for result in search_results {
-                                    writer.write_all(result.as_bytes()).await.unwrap();
-                                    writer.write_all(b"\n").await.unwrap();
-                                }
-                            } else {
-                                writer.write_all(b"No results found.\n").await.unwrap();
-                            }
-                            writer.flush().await.unwrap();
-                        } else {
-                            let msg = Message {
-                                sender_id: user.name.clone(),
-                                content: content.clone(),
-                                timestamp: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
-                            };
-                            chat_manager.store_message(msg.clone());
-                            tx.send(msg.format()).unwrap();
-                        }
+// Peel leading `--in` and `--order` flags off search arguments, in any order
+fn parse_search_args(args: &str, field: SearchField) -> Result<(SearchField, SearchOrder, &str), &'static str> {
+    let (mut field, mut order, mut rest) = (field, SearchOrder::Oldest, args);
+    while let Some(flagged) = rest.strip_prefix("--") {
+        let (flag, after) = flagged.split_once(' ').ok_or(SEARCH_USAGE)?;
+        let (value, after) = after.trim_start().split_once(' ').unwrap_or((after.trim_start(), ""));
+        match (flag, value.to_lowercase().as_str()) {
+            ("in", "content") => field = SearchField::Content,
+            ("in", "sender") => field = SearchField::Sender,
+            ("in", "both") => field = SearchField::Both,
+            ("in", _) => return Err("Search field must be 'content', 'sender' or 'both'."),
+            ("order", "oldest") => order = SearchOrder::Oldest,
+            ("order", "newest") => order = SearchOrder::Newest,
+            ("order", "relevant") => order = SearchOrder::Relevant,
+            ("order", _) => return Err("Order must be 'newest', 'oldest' or 'relevant'."),
+            _ => return Err(SEARCH_USAGE),
+        }
+        rest = after;
+    }
+    if rest.is_empty() {
+        return Err(SEARCH_USAGE);
+    }
+    Ok((field, order, rest))
+}
 
-                        line.clear();
-                    }
-                    result = rx.recv() => {
-                        if let Ok(msg) = result {
-                            writer.write_all(msg.as_bytes()).await.unwrap();
-                            writer.write_all(b"\n").await.unwrap();
-                            writer.flush().await.unwrap();
-                        }
-                    }
+// Broadcasts the channel holds for a client before it drops the oldest
+const BROADCAST_CAPACITY: usize = 100;
+// Upper bound on broadcasts coalesced into a single write
+const MAX_BROADCAST_BATCH: usize = 64;
+
+// Telnet protocol bytes (RFC 854)
+const TELNET_IAC: u8 = 255;
+const TELNET_SB: u8 = 250;
+const TELNET_SE: u8 = 240;
+const TELNET_WILL: u8 = 251;
+const TELNET_DONT: u8 = 254;
+
+// Drop telnet IAC negotiation and subnegotiation sequences, keeping escaped 0xFF data bytes
+fn strip_telnet_commands(bytes: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != TELNET_IAC {
+            data.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes.get(i + 1).copied() {
+            Some(TELNET_IAC) => {
+                data.push(TELNET_IAC);
+                i += 2;
+            }
+            Some(TELNET_WILL..=TELNET_DONT) => i += 3,
+            Some(TELNET_SB) => {
+                // Skip to the closing IAC SE
+                i += 2;
+                while i < bytes.len() && !(bytes[i] == TELNET_IAC && bytes.get(i + 1) == Some(&TELNET_SE)) {
+                    i += 1;
                 }
+                i += 2;
             }
+            _ => i += 2,
+        }
+    }
+    data
+}
+
+// Turn a raw input line into trimmed text, tolerating telnet noise and invalid UTF-8
+fn decode_line(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(&strip_telnet_commands(bytes)).trim().to_string()
+}
+
+// Cap text at `max` characters rather than bytes, so emoji count as one and the cut never splits one
+fn truncate_chars(text: &str, max: usize) -> &str {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+const MAX_QUIT_MESSAGE_LEN: usize = 100;
+
+// Strip control characters and cap the length of a /quit parting line
+fn sanitize_quit_message(message: &str) -> String {
+    message.chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_QUIT_MESSAGE_LEN)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+// What the handler will do with an input line
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Quit,
+    // Carries the field searched when no `--in` flag is given
+    Search(SearchField),
+    Debug,
+    Motd,
+    ClearView,
+    Count,
+    Whois,
+    Whoami,
+    Stats,
+    Leaderboard,
+    Summary,
+    Poll,
+    Vote,
+    PollResults,
+    Mentions,
+    Silence,
+    Encoding,
+    Retention,
+    Caps,
+    Undo,
+    Notify,
+    Resync,
+    // A slash line that isn't a command, or a command with the wrong arguments
+    Unknown,
+    Chat,
+}
+
+// Every command Action::parse recognises, for /caps
+const COMMANDS: &[&str] = &[
+    "/quit", "exit", "/search", "/user", "/count", "/whois", "/whoami", "/stats",
+    "/leaderboard", "/summary", "/mentions", "/poll", "/vote", "/poll-results",
+    "/motd", "/silence", "/encoding", "/retention", "/notify", "/resync", "/caps", "/undo", "/clearview", "/debug",
+];
+
+impl Action {
+    // A line starting with one `/` must be a recognised command with the right
+    // arguments, or it is Unknown and refused; anything else is chat
+    fn parse(command: &str, args: &str) -> Action {
+        match command {
+            "exit" if args.is_empty() => Action::Quit,
+            "/quit" => Action::Quit,
+            "/search" if !args.is_empty() => Action::Search(SearchField::Content),
+            "/user" if !args.is_empty() => Action::Search(SearchField::Sender),
+            "/debug" => Action::Debug,
+            "/motd" if args.is_empty() => Action::Motd,
+            "/clearview" if args.is_empty() => Action::ClearView,
+            "/count" if !args.is_empty() => Action::Count,
+            "/whois" if !args.is_empty() => Action::Whois,
+            "/whoami" if args.is_empty() => Action::Whoami,
+            "/stats" if args.is_empty() => Action::Stats,
+            "/leaderboard" => Action::Leaderboard,
+            "/summary" => Action::Summary,
+            "/poll" => Action::Poll,
+            "/vote" => Action::Vote,
+            "/poll-results" if !args.is_empty() => Action::PollResults,
+            "/mentions" => Action::Mentions,
+            "/silence" => Action::Silence,
+            "/encoding" => Action::Encoding,
+            "/retention" => Action::Retention,
+            "/caps" if args.is_empty() => Action::Caps,
+            "/undo" if args.is_empty() => Action::Undo,
+            "/notify" => Action::Notify,
+            "/resync" => Action::Resync,
+            _ if command.starts_with('/') && !command.starts_with("//") => Action::Unknown,
+            _ => Action::Chat,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Action::Quit => "leave the chat",
+            Action::Search(_) => "search messages",
+            Action::Debug => "show or toggle debug echo",
+            Action::Motd => "show the message of the day",
+            Action::ClearView => "clear your screen",
+            Action::Count => "count messages mentioning a keyword",
+            Action::Whois => "show a user's details",
+            Action::Whoami => "show your own details",
+            Action::Stats => "show server statistics",
+            Action::Leaderboard => "show the most active users",
+            Action::Summary => "summarize recent activity",
+            Action::Poll => "start a poll",
+            Action::Vote => "vote in a poll",
+            Action::PollResults => "show a poll's votes",
+            Action::Mentions => "show messages that mention you",
+            Action::Silence => "pause or resume incoming messages",
+            Action::Encoding => "show or set your output encoding",
+            Action::Retention => "show or set how long your messages are kept",
+            Action::Caps => "list what the server supports",
+            Action::Undo => "retract your last message",
+            Action::Notify => "manage your highlight words",
+            Action::Resync => "show recent messages again after falling behind",
+            Action::Unknown => "refuse: not a command, or the wrong arguments for one",
+            Action::Chat => "broadcast as a chat message",
+        }
+    }
+}
+
+// A leading `//` sends a literal slash, so `//search` is chat reading `/search`.
+// `//search` never matches a command, so only the chat path needs to unescape it.
+fn unescape_chat(content: &str) -> &str {
+    if content.starts_with("//") { &content[1..] } else { content }
+}
+
+// Multi-line MOTDs start below the heading instead of beside it
+fn format_motd(motd: &str) -> String {
+    if motd.contains('\n') {
+        format!("Message of the day:\n{}", motd)
+    } else {
+        format!("Message of the day: {}", motd)
+    }
+}
 
-            chat_manager.remove_user(&addr);
-            tx.send(format!("{} has left.", user.name)).unwrap();
+// Split a line into its lowercased command token and the untouched arguments
+fn split_command(content: &str) -> (String, &str) {
+    match content.split_once(' ') {
+        Some((command, args)) => (command.to_lowercase(), args),
+        None => (content.to_lowercase(), ""),
+    }
+}
+
+// Most output a client may have queued before it is treated as stalled
+const MAX_QUEUED_OUTPUT: usize = 1024 * 1024;
+// Longest one write may wait on a client that isn't reading
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Output to one client. write/line/block only buffer; flush hands the buffer
+// to a dedicated writer task in one chunk, so a slow reader can never block
+// the handler in the middle of a big search dump or stop it from reading
+// input. The handler flushes once per turn, right before it waits for the
+// next event, so no reply is ever left sitting in the buffer. If the client
+// falls too far behind, or the socket fails, the output is marked closed and
+// further writes are dropped; the handler checks is_closed() each turn and
+// disconnects the client. A write that makes no progress for WRITE_TIMEOUT
+// also closes the output, so a client that never reads can't hold its
+// socket and buffer forever.
+struct ClientWriter {
+    crlf: bool,
+    // Set by `/encoding ascii` for terminals that garble multibyte characters
+    ascii: bool,
+    buffer: Vec<u8>,
+    chunks: mpsc::UnboundedSender<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+    closed: Arc<AtomicBool>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl ClientWriter {
+    fn spawn<W: AsyncWrite + Unpin + Send + 'static>(mut writer: W, crlf: bool) -> Self {
+        let (chunks, mut pending) = mpsc::unbounded_channel::<Vec<u8>>();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (task_queued, task_closed) = (queued.clone(), closed.clone());
+        let task = tokio::spawn(async move {
+            while let Some(chunk) = pending.recv().await {
+                let written = tokio::time::timeout(WRITE_TIMEOUT, writer.write_all(&chunk)).await;
+                task_queued.fetch_sub(chunk.len(), Ordering::Relaxed);
+                if !matches!(written, Ok(Ok(()))) {
+                    task_closed.store(true, Ordering::Relaxed);
+                    break;
+                }
+            }
         });
+        ClientWriter { crlf, ascii: false, buffer: Vec::new(), chunks, queued, closed, task }
+    }
+
+    // Text as-is, for prompts and replies that carry their own newlines
+    fn write(&mut self, text: &str) {
+        if self.is_closed() {
+            return;
+        }
+        let text = if self.ascii && !text.is_ascii() {
+            Cow::Owned(text.chars().map(|c| if c.is_ascii() { c } else { '?' }).collect())
+        } else {
+            Cow::Borrowed(text)
+        };
+        self.buffer.extend_from_slice(&apply_newline_style(text.as_bytes(), self.crlf));
+        if self.queued.load(Ordering::Relaxed) + self.buffer.len() > MAX_QUEUED_OUTPUT {
+            self.closed.store(true, Ordering::Relaxed);
+            self.buffer.clear();
+        }
+    }
+
+    fn line(&mut self, text: &str) {
+        self.write(text);
+        self.write("\n");
+    }
+
+    fn block(&mut self, lines: &[&str]) {
+        for line in lines {
+            self.line(line);
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.buffer.is_empty() || self.is_closed() {
+            return;
+        }
+        let chunk = std::mem::take(&mut self.buffer);
+        self.queued.fetch_add(chunk.len(), Ordering::Relaxed);
+        if self.chunks.send(chunk).is_err() {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
     }
 }
 
+// Output that was cut off will never be read, so free the socket now rather
+// than after the stuck write times out. Otherwise the task finishes sending
+// what is queued, like a parting notice, and then exits on its own.
+impl Drop for ClientWriter {
+    fn drop(&mut self) {
+        if self.is_closed() {
+            self.task.abort();
+        }
+    }
+}
+
+// Output is composed with bare `\n`; with --crlf every one goes out as `\r\n`
+fn apply_newline_style(bytes: &[u8], crlf: bool) -> Vec<u8> {
+    if !crlf {
+        return bytes.to_vec();
+    }
+    let mut out = Vec::with_capacity(bytes.len() + bytes.len() / 16);
+    for (i, &byte) in bytes.iter().enumerate() {
+        if byte == b'\n' && (i == 0 || bytes[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(byte);
+    }
+    out
+}
+
+// How long after sending a message /undo can still take it back
+const UNDO_WINDOW: Duration = Duration::from_secs(2 * 60);
+
+const MAX_RETENTION_HOURS: i64 = 365 * 24;
+// How often messages past their sender's /retention are deleted
+const RETENTION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+const SILENCE_USAGE: &str = "Usage: /silence <seconds> [drop|buffer] or /silence off";
+const MAX_SILENCE_SECS: u64 = 3600;
+// Broadcasts a buffering /silence holds on to; the rest count as missed
+const MAX_SILENCE_HELD: usize = 500;
+
+// Broadcasts held back or dropped while /silence is on
+struct Silence {
+    until: tokio::time::Instant,
+    // Some when buffering for replay on resume, None when dropping
+    held: Option<Vec<String>>,
+    missed: usize,
+}
+
+impl Silence {
+    fn hold(&mut self, batch: Vec<String>) {
+        for msg in batch {
+            match &mut self.held {
+                Some(held) if held.len() < MAX_SILENCE_HELD => held.push(msg),
+                _ => self.missed += 1,
+            }
+        }
+    }
+
+    fn resume(self, writer: &mut ClientWriter) {
+        for msg in self.held.iter().flatten() {
+            writer.line(msg);
+        }
+        match self.missed {
+            0 => writer.line("Output resumed."),
+            missed => writer.line(&format!("Output resumed, you may have missed {} messages.", missed)),
+        }
+    }
+}
+
+// `<seconds> [drop|buffer]` gives the length and whether to buffer; `off` gives None
+fn parse_silence_args(args: &str) -> Result<Option<(Duration, bool)>, &'static str> {
+    let words: Vec<&str> = args.split_whitespace().collect();
+    let (seconds, buffer) = match words.as_slice() {
+        ["off"] => return Ok(None),
+        [seconds] => (seconds, false),
+        [seconds, mode] if mode.eq_ignore_ascii_case("drop") => (seconds, false),
+        [seconds, mode] if mode.eq_ignore_ascii_case("buffer") => (seconds, true),
+        _ => return Err(SILENCE_USAGE),
+    };
+    let seconds = seconds.parse::<u64>().ok()
+        .filter(|s| (1..=MAX_SILENCE_SECS).contains(s))
+        .ok_or("Silence length must be 1 to 3600 seconds.")?;
+    Ok(Some((Duration::from_secs(seconds), buffer)))
+}
+
+const RESYNC_USAGE: &str = "Usage: /resync [count] or /resync auto on|off";
+// Most messages one resync replays; a client can fall further behind than this
+const MAX_RESYNC: usize = 100;
+const DEFAULT_RESYNC: usize = 20;
+
+// Write the last `count` stored messages, marked off so they read as a replay
+fn resync(chat_manager: &ChatManager, writer: &mut ClientWriter, count: usize) {
+    let lines = chat_manager.recent(count.min(MAX_RESYNC));
+    if lines.is_empty() {
+        writer.line("No messages to resync.");
+        return;
+    }
+    writer.line(&format!("--- last {} messages ---", lines.len()));
+    for line in lines {
+        writer.line(&line);
+    }
+    writer.line("--- end of resync ---");
+}
+
+// After falling behind by `missed`, replay the stored messages just before
+// `queued`, the broadcasts still waiting for this client, so the gap is
+// filled without repeating what is about to arrive
+fn backfill(chat_manager: &ChatManager, writer: &mut ClientWriter, missed: usize, queued: &[String]) {
+    let replay = missed.min(MAX_RESYNC);
+    // Room for messages stored after the queued ones, too
+    let lines = chat_manager.recent(replay + queued.len() + BROADCAST_CAPACITY);
+    let end = queued.iter()
+        .find_map(|line| lines.iter().rposition(|stored| stored == line))
+        .unwrap_or(lines.len());
+    let gap = &lines[end.saturating_sub(replay)..end];
+    if gap.is_empty() {
+        return;
+    }
+    writer.line(&format!("--- {} missed messages ---", gap.len()));
+    for line in gap {
+        writer.line(line);
+    }
+    writer.line("--- end of resync ---");
+}
+
+// Mark broadcasts for /notify, skipping this connection's own lines. Lines
+// lost to lag never come back, so `sent` is cleared up to each one found.
+fn mark_batch(batch: Vec<String>, sent: &mut VecDeque<String>, highlights: &Highlights) -> Vec<String> {
+    batch.into_iter().map(|msg| {
+        let own = sent.iter().position(|line| *line == msg).map(|at| sent.drain(..=at));
+        highlights.mark(msg, own.is_some())
+    }).collect()
+}
+
+const NOTIFY_USAGE: &str = "Usage: /notify add <word>, /notify remove <word> or /notify list";
+const MAX_NOTIFY_WORDS: usize = 20;
+const MAX_NOTIFY_WORD_LEN: usize = 32;
+
+// Words one client wants highlighted in what it is sent, like IRC highlight
+// words. Kept lowercase and matched as whole words, ignoring case.
+#[derive(Default)]
+struct Highlights {
+    words: Vec<String>,
+}
+
+impl Highlights {
+    fn add(&mut self, word: &str) -> Result<String, &'static str> {
+        let word = word.to_lowercase();
+        if word.chars().count() > MAX_NOTIFY_WORD_LEN {
+            return Err("Notify words can be at most 32 characters.");
+        }
+        if self.words.contains(&word) {
+            return Ok(format!("Already notifying on '{}'.", word));
+        }
+        if self.words.len() >= MAX_NOTIFY_WORDS {
+            return Err("You can notify on at most 20 words; remove one first.");
+        }
+        let reply = format!("Notifying on '{}'.", word);
+        self.words.push(word);
+        Ok(reply)
+    }
+
+    fn remove(&mut self, word: &str) -> String {
+        let word = word.to_lowercase();
+        match self.words.iter().position(|w| *w == word) {
+            Some(index) => {
+                self.words.remove(index);
+                format!("No longer notifying on '{}'.", word)
+            }
+            None => format!("You weren't notifying on '{}'.", word),
+        }
+    }
+
+    // A bell and a marker in front of a line that contains one of the words.
+    // `own` lines, the client's own chat, are never marked.
+    fn mark(&self, line: String, own: bool) -> String {
+        if self.words.is_empty() || own {
+            return line;
+        }
+        let lower = line.to_lowercase();
+        let found = self.words.iter().any(|word| {
+            lower.match_indices(word.as_str()).any(|(at, _)| {
+                let before = lower[..at].chars().next_back();
+                let after = lower[at + word.len()..].chars().next();
+                !before.is_some_and(is_name_char) && !after.is_some_and(is_name_char)
+            })
+        });
+        if found { format!("\x07>> {}", line) } else { line }
+    }
+}
+
+// Watch the chat without joining: no join or leave notice, not counted as
+// online, and nothing but /quit is accepted
+async fn spectate<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+    writer: &mut ClientWriter,
+    rx: &mut broadcast::Receiver<String>,
+) {
+    writer.line("Spectating. Type /quit to leave.");
+    let mut line = Vec::new();
+    loop {
+        writer.flush();
+        if writer.is_closed() {
+            break;
+        }
+        tokio::select! {
+            result = reader.read_until(b'\n', &mut line) => {
+                if !matches!(result, Ok(n) if n > 0) { break; }
+                let content = decode_line(&line);
+                line.clear();
+                let (command, args) = split_command(&content);
+                if Action::parse(&command, args) == Action::Quit {
+                    break;
+                }
+                writer.line("Spectators can't post or run commands. Type /quit to leave.");
+            }
+            result = rx.recv() => {
+                if let Ok(msg) = result {
+                    writer.line(&msg);
+                }
+            }
+        }
+    }
+}
+
+// Send a best-effort notice and close; never block the accept loop on the refused client
+fn refuse(mut socket: TcpStream, notice: &'static [u8], crlf: bool) {
+    tokio::spawn(async move {
+        let notice = apply_newline_style(notice, crlf);
+        let _ = tokio::time::timeout(Duration::from_secs(1), socket.write_all(&notice)).await;
+    });
+}
+
+// A socket file left behind by an earlier run would make bind fail; anything
+// else already at the path is left alone and reported by bind
+#[cfg(unix)]
+fn bind_unix(path: &str) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+// Process exit codes, so a supervisor can tell a bad setup from a crash.
+// A clean shutdown on Ctrl-C or SIGTERM exits with 0.
+const EXIT_SERVE_ERROR: u8 = 1;
+const EXIT_CONFIG_ERROR: u8 = 2;
+const EXIT_BIND_ERROR: u8 = 3;
+const EXIT_STORAGE_ERROR: u8 = 4;
+
+// Why the server stopped serving
+enum Fatal {
+    Bind(String, io::Error),
+    Storage(String),
+    Serve(io::Error),
+}
+
+impl Fatal {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Fatal::Bind(..) => EXIT_BIND_ERROR,
+            Fatal::Storage(_) => EXIT_STORAGE_ERROR,
+            Fatal::Serve(_) => EXIT_SERVE_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for Fatal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Fatal::Bind(addr, e) => write!(f, "cannot listen on {}: {}", addr, e),
+            Fatal::Storage(e) => write!(f, "{}", e),
+            Fatal::Serve(e) => write!(f, "cannot accept connections: {}", e),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(EXIT_CONFIG_ERROR);
+        }
+    };
+    let result = tokio::select! {
+        result = serve(config) => result,
+        () = shutdown_signal() => Ok(()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(fatal) => {
+            eprintln!("error: {}", fatal);
+            ExitCode::from(fatal.exit_code())
+        }
+    }
+}
+
+// Resolves on Ctrl-C, or on SIGTERM where there is one
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+async fn serve(config: Config) -> Result<(), Fatal> {
+    let (tx, _rx) = broadcast::channel(BROADCAST_CAPACITY);
+    let webhook = match (&config.webhook_bind, &config.webhook_secret) {
+        (Some(bind), Some(secret)) => {
+            let listener = TcpListener::bind(bind).await.map_err(|e| Fatal::Bind(bind.clone(), e))?;
+            Some((listener, secret.clone()))
+        }
+        _ => None,
+    };
+    // Bounds how many connections are being served at once; the rest wait their turn
+    let handler_permits = Arc::new(Semaphore::new(config.max_handlers));
+    let messages: Box<dyn MessageStore> = match &config.db {
+        Some(path) => Box::new(SqliteStore::open(path).map_err(Fatal::Storage)?),
+        None => Box::new(MemoryStore::default()),
+    };
+    let chat_manager = Arc::new(ChatManager::new(config, Arc::new(SystemClock), messages));
+
+    // Both subscribe before the first connection, so they see every event
+    tokio::spawn(events::record_metrics(chat_manager.events.subscribe_to_every(), chat_manager.metrics.clone()));
+    if chat_manager.config.log {
+        tokio::spawn(events::log(chat_manager.events.subscribe(), chat_manager.clock.clone()));
+    }
+
+    let sweeper = chat_manager.clone();
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(RETENTION_SWEEP_INTERVAL);
+        loop {
+            ticks.tick().await;
+            // A sweep waits on the database, so keep it off the async workers
+            let sweeper = sweeper.clone();
+            let _ = tokio::task::spawn_blocking(move || sweeper.sweep_expired()).await;
+        }
+    });
+
+    if let Some((webhook_listener, secret)) = webhook {
+        tokio::spawn(webhook::serve(webhook_listener, secret, chat_manager.clone(), tx.clone()));
+    }
+
+    // Local clients are trusted by file permissions, so --allow and the rate limit don't apply
+    #[cfg(unix)]
+    if let Some(path) = &chat_manager.config.unix {
+        let listener = bind_unix(path).map_err(|e| Fatal::Bind(path.clone(), e))?;
+        loop {
+            let (socket, _) = listener.accept().await.map_err(Fatal::Serve)?;
+            let client = handle_client(socket, Peer::Unix, chat_manager.clone(), tx.clone(), handler_permits.clone());
+            tokio::spawn(client);
+        }
+    }
+
+    let bind = "127.0.0.1:8080";
+    let listener = TcpListener::bind(bind).await.map_err(|e| Fatal::Bind(bind.to_string(), e))?;
+    loop {
+        let (socket, addr) = listener.accept().await.map_err(Fatal::Serve)?;
+        if !chat_manager.is_allowed(addr.ip()) {
+            refuse(socket, b"Connections from your address are not allowed.\n", chat_manager.config.crlf);
+            continue;
+        }
+        if !chat_manager.allow_connection(addr.ip()) {
+            refuse(socket, b"Too many connections from your address, try again later.\n", chat_manager.config.crlf);
+            continue;
+        }
+        let client = handle_client(socket, Peer::Tcp(addr), chat_manager.clone(), tx.clone(), handler_permits.clone());
+        tokio::spawn(client);
+    }
+}
+
+// Serve one client from handler slot to leave notice; any byte stream will do
+async fn handle_client<S>(
+    mut socket: S,
+    peer: Peer,
+    chat_manager: Arc<ChatManager>,
+    tx: broadcast::Sender<String>,
+    handler_permits: Arc<Semaphore>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let _permit = match handler_permits.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            let notice = apply_newline_style(b"Server busy, waiting for a free slot...\n", chat_manager.config.crlf);
+            if socket.write_all(&notice).await.is_err() {
+                return;
+            }
+            handler_permits.acquire_owned().await.unwrap()
+        }
+    };
+    chat_manager.events.emit(Event::Connected { peer });
+    // Subscribe only once served, so a waiting client doesn't pile up broadcasts
+    let mut rx = tx.subscribe();
+    let (reader, writer) = tokio::io::split(socket);
+    let mut writer = ClientWriter::spawn(writer, chat_manager.config.crlf);
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+
+    if let Some(banner) = &chat_manager.config.banner {
+        writer.write(banner);
+    }
+
+    // Get user's name, after an optional HELLO handshake from smart clients;
+    // SPECTATE instead of a name watches without joining
+    let mut greeted = false;
+    let deadline = tokio::time::Instant::now() + chat_manager.config.name_timeout;
+    let connection = chat_manager.next_connection_id();
+    let user = loop {
+        writer.write("Enter your name: ");
+        writer.flush();
+        // A client that disconnects or stalls before naming itself never joins
+        match tokio::time::timeout_at(deadline, reader.read_until(b'\n', &mut line)).await {
+            Ok(Ok(0) | Err(_)) => return,
+            Ok(Ok(_)) => {}
+            Err(_) => {
+                writer.write("\nTimed out waiting for a name.\n");
+                writer.flush();
+                return;
+            }
+        }
+        let first = decode_line(&line);
+        line.clear();
+        if first.eq_ignore_ascii_case("SPECTATE") {
+            spectate(&mut reader, &mut writer, &mut rx).await;
+            return;
+        }
+        match first.split_once(' ') {
+            Some((hello, version)) if !greeted && hello.eq_ignore_ascii_case("HELLO") => {
+                greeted = true;
+                let reply = chat_manager.hello(version.trim());
+                writer.write(&reply);
+            }
+            _ => match check_name(&first) {
+                Ok(()) => match chat_manager.register_user(connection, peer, first) {
+                    Ok(user) => break user,
+                    Err(reason) => writer.line(&reason),
+                },
+                Err(reason) => writer.line(reason),
+            },
+        }
+    };
+
+    // Show command instructions in a box
+    writer.block(&[
+        "",
+        "",
+        "***************************************************",
+        "- Type '/quit [message]' or 'exit' to leave",
+        "- Type '/search <query>' to search by keyword",
+        "  (add '--in sender' or '--in both' to search sender names too)",
+        "- Type '/user <username>' to search by user",
+        "  (add '--order newest' before the query to see recent matches first)",
+        "- Type '/count <keyword>' to count messages mentioning a keyword",
+        "- Type '/whois <username>' to see a user's details",
+        "- Type '/whoami' to see your own name, id and connection",
+        "- Type '/stats' to see server statistics",
+        "- Type '/leaderboard [n]' to see the most active users",
+        "- Type '/summary [minutes]' to see recent activity",
+        "- Type '/mentions [count]' to see recent messages that @mention you",
+        "- Type '/poll \"Question?\" <option> <option>...' to start a poll",
+        "  (then '/vote <id> <option>' and '/poll-results <id>')",
+        "- Type '/motd' to see the message of the day",
+        "- Type '/silence <seconds>' to pause incoming messages for a while",
+        "- Type '/encoding ascii' if accented letters or emoji look garbled",
+        "- Type '/retention <hours>' to have your messages deleted after a while",
+        "- Type '/notify add <word>' to be alerted when a word comes up",
+        "- Type '/resync' to catch up if the server says you fell behind",
+        "- Type '/undo' within 2 minutes to retract your last message",
+        "- Type '/caps' to see what this server supports",
+        "- Type '/clearview' to clear your screen",
+        "- Type '/debug on|off' to see how your input is parsed",
+        "- Type any other message to chat",
+        "***************************************************",
+        "",
+        "",
+    ]);
+    // Kept for /motd so this connection re-reads the message it was greeted with
+    let motd = chat_manager.pick_motd();
+    if let Some(motd) = &motd {
+        writer.block(&[&format_motd(motd), ""]);
+    }
+
+    // Notify others of join with new format
+    let join_msg = format!("\n\n*** {} has joined at {} ***\n\n", 
+        user.name,
+        chat_manager.timestamp()
+    );
+    tx.send(join_msg).unwrap();
+
+    // Echo back how each line was parsed; starts on with --echo, toggled by /debug
+    let mut debug = chat_manager.config.echo;
+    // Consecutive lines that looked like commands but weren't recognised
+    let mut unknown_commands = 0;
+    // Set by /silence while broadcasts are held back
+    let mut silence: Option<Silence> = None;
+    let mut highlights = Highlights::default();
+    // With /resync auto on, falling behind replays what was missed straight away
+    let mut auto_resync = false;
+    // Hours from /retention that this connection's messages are kept for; None keeps them forever
+    let mut retention: Option<i64> = None;
+    // Lines this connection posted and hasn't seen come back yet, so /notify skips them
+    let mut sent: VecDeque<String> = VecDeque::new();
+    // A turn that showed nothing, like a broadcast held back by /silence, needs no fresh prompt
+    let mut skip_prompt = false;
+
+    loop {
+        // Everything the last turn wrote goes out together with the prompt
+        if let Some(prompt) = &chat_manager.config.prompt
+            && !std::mem::take(&mut skip_prompt)
+        {
+            writer.write(prompt);
+        }
+        writer.flush();
+        // The client stopped reading or its socket failed; treat it as gone
+        if writer.is_closed() {
+            break;
+        }
+        let silence_ends = silence.as_ref().map_or_else(tokio::time::Instant::now, |s| s.until);
+        tokio::select! {
+            result = reader.read_until(b'\n', &mut line) => {
+                // EOF or a reset connection both mean the client is gone
+                if !matches!(result, Ok(n) if n > 0) { break; }
+                let content = decode_line(&line);
+                let (command, args) = split_command(&content);
+                let action = Action::parse(&command, args);
+
+                if debug {
+                    let report = match action {
+                        Action::Chat => format!("[debug] text='{}' -> {}", content, action.describe()),
+                        _ => format!("[debug] command='{}' args='{}' -> {}", command, args, action.describe()),
+                    };
+                    writer.line(&report);
+                }
+
+                // Protects against scripted clients stuck sending the same bad command
+                if action == Action::Unknown {
+                    unknown_commands += 1;
+                } else {
+                    unknown_commands = 0;
+                }
+                let limit = chat_manager.config.max_unknown_commands;
+                if limit > 0 && unknown_commands >= limit {
+                    writer.line("Too many unknown commands in a row, disconnecting.");
+                    writer.flush();
+                    break;
+                }
+
+                // A slash line that didn't parse as a command is a typo, not chat,
+                // so it is refused instead of posted
+                if action == Action::Unknown {
+                    let reply = if COMMANDS.contains(&command.as_str()) {
+                        format!("Wrong arguments for {}.", command)
+                    } else {
+                        format!("Unknown command {}.", command)
+                    };
+                    writer.line(&format!("{} Start the line with // to send it as chat.", reply));
+                    if limit > 1 && unknown_commands == limit - 1 {
+                        writer.line("One more unknown command in a row and you will be disconnected.");
+                    }
+                } else if action == Action::Quit {
+                    // Notify others of leave with new format, plus any parting line
+                    let parting = sanitize_quit_message(args);
+                    let leave_msg = format!("\n\n*** {} has left at {}{} ***\n\n",
+                        user.name,
+                        chat_manager.timestamp(),
+                        if parting.is_empty() { String::new() } else { format!(": {}", parting) }
+                    );
+                    chat_manager.remove_user(connection);
+                    tx.send(leave_msg).unwrap();
+                    // This notice replaces the plain one after the loop
+                    return;
+                } else if let Action::Search(field) = action {
+                    match parse_search_args(args, field) {
+                        Ok((field, order, query)) => match chat_manager.search_messages(query, field, order) {
+                            Ok(search_results) if !search_results.is_empty() => {
+                                writer.line(&format!("Search results by {}:", field.label()));
+                                for result in search_results {
+                                    writer.line(&result);
+                                }
+                            }
+                            Ok(_) => writer.line("No results found."),
+                            Err(error) => writer.line(error),
+                        },
+                        Err(error) => writer.line(error),
+                    }
+                } else if action == Action::Debug {
+                    match args.trim().to_lowercase().as_str() {
+                        "on" => debug = true,
+                        "off" => debug = false,
+                        _ => {}
+                    }
+                    writer.line(if debug { "Debug echo is on." } else { "Debug echo is off." });
+                } else if action == Action::Motd {
+                    match &motd {
+                        Some(motd) => writer.line(&format_motd(motd)),
+                        None => writer.line("No MOTD set."),
+                    }
+                } else if action == Action::ClearView {
+                    // Clear the screen and home the cursor; history on the server is untouched
+                    writer.write("\x1b[2J\x1b[H");
+                } else if action == Action::Count {
+                    let count = chat_manager.count_messages_by_keyword(args);
+                    writer.line(&format!("{} messages mention '{}'", count, args));
+                } else if action == Action::Whois {
+                    for detail in chat_manager.whois(args.trim()) {
+                        writer.line(&detail);
+                    }
+                } else if action == Action::Whoami {
+                    for detail in chat_manager.whoami(&user) {
+                        writer.line(&detail);
+                    }
+                } else if action == Action::Stats {
+                    for line in chat_manager.stats(&user.name) {
+                        writer.line(&line);
+                    }
+                } else if action == Action::Leaderboard {
+                    let n = match args.trim() {
+                        "" => Some(10),
+                        n => n.parse::<usize>().ok().filter(|n| *n > 0),
+                    };
+                    match n {
+                        Some(n) => {
+                            let leaders = chat_manager.leaderboard(n.min(50));
+                            if leaders.is_empty() {
+                                writer.line("No messages yet.");
+                            }
+                            for (rank, (name, count)) in leaders.iter().enumerate() {
+                                let noun = if *count == 1 { "message" } else { "messages" };
+                                writer.line(&format!("{}. {} ({} {})", rank + 1, name, count, noun));
+                            }
+                        }
+                        None => writer.line("Usage: /leaderboard [n]"),
+                    }
+                } else if action == Action::Summary {
+                    let minutes = match args.trim() {
+                        "" => Some(60),
+                        m => m.parse::<i64>().ok().filter(|m| (1..=7 * 24 * 60).contains(m)),
+                    };
+                    match minutes {
+                        Some(minutes) => {
+                            for line in chat_manager.summary(minutes) {
+                                writer.line(&line);
+                            }
+                        }
+                        None => writer.line("Usage: /summary [minutes]"),
+                    }
+                } else if action == Action::Silence {
+                    match parse_silence_args(args) {
+                        Ok(Some((duration, buffer))) => {
+                            // A new /silence starts over; anything the old one held is shown first
+                            if let Some(previous) = silence.take() {
+                                previous.resume(&mut writer);
+                            }
+                            silence = Some(Silence {
+                                until: tokio::time::Instant::now() + duration,
+                                held: buffer.then(Vec::new),
+                                missed: 0,
+                            });
+                            let treatment = if buffer { "held until then" } else { "dropped" };
+                            writer.line(&format!(
+                                "Output silenced for {} seconds; new messages are {}. Type '/silence off' to resume.",
+                                duration.as_secs(), treatment
+                            ));
+                        }
+                        Ok(None) => match silence.take() {
+                            Some(silence) => silence.resume(&mut writer),
+                            None => writer.line("Output isn't silenced."),
+                        },
+                        Err(error) => writer.line(error),
+                    }
+                } else if action == Action::Encoding {
+                    match args.trim().to_lowercase().as_str() {
+                        "ascii" => writer.ascii = true,
+                        "utf-8" | "utf8" => writer.ascii = false,
+                        "" => {}
+                        _ => writer.line("Usage: /encoding [utf-8|ascii]"),
+                    }
+                    if writer.ascii {
+                        writer.line("Output encoding is ASCII; other characters are shown as '?'.");
+                    } else {
+                        writer.line("Output encoding is UTF-8.");
+                    }
+                } else if action == Action::Notify {
+                    let words: Vec<&str> = args.split_whitespace().collect();
+                    match words.as_slice() {
+                        [] | ["list"] if highlights.words.is_empty() => writer.line("You have no notify words."),
+                        [] | ["list"] => writer.line(&format!("Notifying on: {}", highlights.words.join(", "))),
+                        ["add", word] => match highlights.add(word) {
+                            Ok(reply) => writer.line(&reply),
+                            Err(error) => writer.line(error),
+                        },
+                        ["remove", word] => writer.line(&highlights.remove(word)),
+                        _ => writer.line(NOTIFY_USAGE),
+                    }
+                } else if action == Action::Resync {
+                    let words: Vec<&str> = args.split_whitespace().collect();
+                    match words.as_slice() {
+                        [] => resync(&chat_manager, &mut writer, DEFAULT_RESYNC),
+                        ["auto", "on"] => {
+                            auto_resync = true;
+                            writer.line("Automatic resync is on.");
+                        }
+                        ["auto", "off"] => {
+                            auto_resync = false;
+                            writer.line("Automatic resync is off.");
+                        }
+                        [count] => match count.parse::<usize>().ok().filter(|n| (1..=MAX_RESYNC).contains(n)) {
+                            Some(count) => resync(&chat_manager, &mut writer, count),
+                            None => writer.line(RESYNC_USAGE),
+                        },
+                        _ => writer.line(RESYNC_USAGE),
+                    }
+                } else if action == Action::Undo {
+                    let undo = {
+                        let (chat_manager, user, tx) = (chat_manager.clone(), user.clone(), tx.clone());
+                        tokio::task::spawn_blocking(move || chat_manager.undo(&user, &tx))
+                    };
+                    match undo.await.unwrap() {
+                        Some(msg) => writer.line(&format!("Retracted: {}", msg.content)),
+                        None => writer.line("You sent no message on this connection in the last 2 minutes to undo."),
+                    }
+                } else if action == Action::Caps {
+                    for line in chat_manager.caps() {
+                        writer.line(&line);
+                    }
+                } else if action == Action::Retention {
+                    let hours = match args.trim() {
+                        "" => Ok(None),
+                        hours => hours.parse::<i64>().ok().filter(|h| (0..=MAX_RETENTION_HOURS).contains(h)).map(Some).ok_or(()),
+                    };
+                    match hours {
+                        Ok(hours) => {
+                            if let Some(hours) = hours {
+                                retention = (hours > 0).then_some(hours);
+                            }
+                            match retention {
+                                Some(1) => writer.line("Your messages are deleted an hour after you send them."),
+                                Some(hours) => writer.line(&format!("Your messages are deleted {} hours after you send them.", hours)),
+                                None => writer.line("Your messages are kept forever."),
+                            }
+                        }
+                        Err(()) => writer.line("Usage: /retention [hours], from 0 (forever) to 8760"),
+                    }
+                } else if action == Action::Mentions {
+                    let count = match args.trim() {
+                        "" => Some(10),
+                        n => n.parse::<usize>().ok().filter(|n| *n > 0),
+                    };
+                    match count {
+                        Some(count) => {
+                            let mentions = chat_manager.mentions(&user.name, count.min(50));
+                            if mentions.is_empty() {
+                                writer.line(&format!("Nobody has mentioned @{} yet.", user.name));
+                            }
+                            for mention in mentions {
+                                writer.line(&mention);
+                            }
+                        }
+                        None => writer.line("Usage: /mentions [count]"),
+                    }
+                } else if action == Action::Poll {
+                    match parse_poll_args(args).and_then(|(question, options, duration)| {
+                        chat_manager.open_poll(&user.name, question, options, duration)
+                    }) {
+                        Ok(announcement) => {
+                            tx.send(announcement).unwrap();
+                        }
+                        Err(error) => writer.line(error),
+                    }
+                } else if action == Action::Vote {
+                    let reply = match args.trim().split_once(' ') {
+                        Some((id, choice)) => match id.trim_start_matches('#').parse::<u64>() {
+                            Ok(id) => chat_manager.vote(id, &user.name, choice.trim()).unwrap_or_else(|error| error),
+                            Err(_) => VOTE_USAGE.to_string(),
+                        },
+                        None => VOTE_USAGE.to_string(),
+                    };
+                    writer.line(&reply);
+                } else if action == Action::PollResults {
+                    let id = args.trim().trim_start_matches('#');
+                    match id.parse::<u64>().ok().and_then(|id| chat_manager.poll_results(id)) {
+                        Some(lines) => {
+                            for line in lines {
+                                writer.line(&line);
+                            }
+                        }
+                        None => writer.line(&format!("No poll #{}.", id)),
+                    }
+                } else {
+                    let content = unescape_chat(&content);
+                    let max_len = chat_manager.config.max_message_len;
+                    let kept = truncate_chars(content, max_len);
+                    if kept.len() < content.len() {
+                        writer.line(&format!("Message cut to {} characters.", max_len));
+                    }
+                    let content = kept;
+                    let msg = Message {
+                        sender_id: user.name.clone(),
+                        content: content.to_string(),
+                        timestamp: chat_manager.timestamp(),
+                        sent_at: chat_manager.sent_at(),
+                        source: user.source,
+                        author: Some(user.id.clone()),
+                        expires_at: retention.map(|hours| chat_manager.expires_at(hours)),
+                    };
+                    sent.push_back(chat_manager.post(msg, &tx));
+                    if sent.len() > MAX_BROADCAST_BATCH {
+                        sent.pop_front();
+                    }
+
+                    // Only client chat reaches here, so the bot never answers itself
+                    if let Some(reply) = chat_manager.bot_reply(content) {
+                        chat_manager.post(reply, &tx);
+                    }
+                }
+
+                line.clear();
+            }
+            result = rx.recv() => {
+                let lagged = match result {
+                    Ok(msg) => {
+                        // Drain whatever else is already queued so a burst costs one write.
+                        // Hitting a gap ends the batch; it is reported after the batch, like a lag from recv.
+                        let mut batch = vec![msg];
+                        let mut lagged = None;
+                        for _ in 1..MAX_BROADCAST_BATCH {
+                            match rx.try_recv() {
+                                Ok(msg) => batch.push(msg),
+                                Err(broadcast::error::TryRecvError::Lagged(missed)) => {
+                                    lagged = Some(missed as usize);
+                                    break;
+                                }
+                                Err(_) => break,
+                            }
+                        }
+                        let batch = mark_batch(batch, &mut sent, &highlights);
+                        if let Some(silence) = &mut silence {
+                            silence.hold(batch);
+                            skip_prompt = true;
+                        } else {
+                            for msg in batch {
+                                writer.line(&msg);
+                            }
+                        }
+                        lagged
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => Some(missed as usize),
+                    Err(broadcast::error::RecvError::Closed) => None,
+                };
+                // Too slow to keep up: the channel dropped the oldest broadcasts for this client.
+                // Only chat is stored, so a replay can't bring back notices.
+                if let Some(mut missed) = lagged {
+                    if let Some(silence) = &mut silence {
+                        silence.missed += missed;
+                        skip_prompt = true;
+                    } else if auto_resync {
+                        // Take what is still queued first, so the replay can stop where it starts
+                        let mut queued = Vec::new();
+                        for _ in 0..rx.len() {
+                            match rx.try_recv() {
+                                Ok(msg) => queued.push(msg),
+                                Err(broadcast::error::TryRecvError::Lagged(more)) => missed += more as usize,
+                                Err(_) => break,
+                            }
+                        }
+                        writer.line(&format!("You fell behind and missed {} messages.", missed));
+                        backfill(&chat_manager, &mut writer, missed, &queued);
+                        for msg in mark_batch(queued, &mut sent, &highlights) {
+                            writer.line(&msg);
+                        }
+                    } else {
+                        writer.line(&format!("You fell behind and missed {} messages. Type /resync to see recent ones.", missed));
+                    }
+                }
+            }
+            () = tokio::time::sleep_until(silence_ends), if silence.is_some() => {
+                silence.take().unwrap().resume(&mut writer);
+            }
+        }
+    }
+
+    chat_manager.remove_user(connection);
+    tx.send(format!("{} has left.", user.name)).unwrap();
+}
+
 // To run a client, you can use `telnet 127.0.0.1 8080` in the terminal.
 // For Go version, a separate implementation would be created using goroutines and net package.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    // A clock that only moves when told to
+    struct ManualClock {
+        now: Mutex<(DateTime<Local>, Instant)>,
+    }
+
+    impl ManualClock {
+        fn new() -> Self {
+            ManualClock { now: Mutex::new((Local::now(), Instant::now())) }
+        }
+
+        fn advance(&self, by: Duration) {
+            let mut now = self.now.lock().unwrap();
+            now.0 += chrono::Duration::from_std(by).unwrap();
+            now.1 += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Local> {
+            self.now.lock().unwrap().0
+        }
+
+        fn instant(&self) -> Instant {
+            self.now.lock().unwrap().1
+        }
+    }
+
+    fn manager(config: Config, clock: Arc<ManualClock>) -> ChatManager {
+        ChatManager::new(config, clock, Box::new(MemoryStore::default()))
+    }
+
+    #[test]
+    fn connection_window_slides_with_the_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let config = Config { max_connections_per_minute: 2, ..Config::default() };
+        let chat_manager = manager(config, clock.clone());
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(chat_manager.allow_connection(ip));
+        clock.advance(Duration::from_secs(30));
+        assert!(chat_manager.allow_connection(ip));
+        assert!(!chat_manager.allow_connection(ip));
+        // The first attempt leaves the window, the second is still in it
+        clock.advance(Duration::from_secs(30));
+        assert!(chat_manager.allow_connection(ip));
+        assert!(!chat_manager.allow_connection(ip));
+    }
+
+    fn chat(chat_manager: &ChatManager, name: &str, content: &str, expires_at: Option<i64>) -> Message {
+        Message {
+            sender_id: name.to_string(),
+            content: content.to_string(),
+            timestamp: chat_manager.timestamp(),
+            sent_at: chat_manager.sent_at(),
+            source: Source::Tcp,
+            author: None,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn only_messages_stamped_with_an_expiry_are_swept() {
+        let clock = Arc::new(ManualClock::new());
+        let chat_manager = manager(Config::default(), clock.clone());
+        let (tx, _rx) = broadcast::channel(16);
+        chat_manager.post(chat(&chat_manager, "alice", "kept", None), &tx);
+        chat_manager.post(chat(&chat_manager, "alice", "expiring", Some(chat_manager.expires_at(1))), &tx);
+        clock.advance(Duration::from_secs(59 * 60));
+        chat_manager.sweep_expired();
+        assert_eq!(chat_manager.recent(10).len(), 2);
+        clock.advance(Duration::from_secs(60));
+        chat_manager.sweep_expired();
+        let left = chat_manager.recent(10);
+        assert_eq!(left.len(), 1);
+        assert!(left[0].ends_with("alice: kept"));
+        assert_eq!(chat_manager.message_count("alice"), 1);
+    }
+
+    // Run `write` against a ClientWriter and return everything it sent
+    async fn output(write: impl FnOnce(&mut ClientWriter)) -> String {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut writer = ClientWriter::spawn(server, false);
+        write(&mut writer);
+        writer.flush();
+        drop(writer);
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn backfill_stops_where_the_queued_broadcasts_start() {
+        let chat_manager = manager(Config::default(), Arc::new(ManualClock::new()));
+        let (tx, _rx) = broadcast::channel(16);
+        let lines: Vec<String> = (1..=10)
+            .map(|n| chat_manager.post(chat(&chat_manager, "alice", &format!("message {}", n), None), &tx))
+            .collect();
+        // Missed 4 through 7; 8 and 9 are still queued; 10 was stored after the lag was noticed
+        let out = output(|writer| backfill(&chat_manager, writer, 4, &lines[7..9])).await;
+        let replayed: Vec<&str> = out.lines().collect();
+        assert_eq!(replayed.first(), Some(&"--- 4 missed messages ---"));
+        assert_eq!(&replayed[1..5], &lines[3..7].iter().map(String::as_str).collect::<Vec<_>>()[..]);
+        assert_eq!(replayed.last(), Some(&"--- end of resync ---"));
+    }
+
+    // Start a handler on one end of an in-memory socket and hand back the other end
+    fn connect(chat_manager: &Arc<ChatManager>, tx: &broadcast::Sender<String>) -> (tokio::io::DuplexStream, tokio::task::JoinHandle<()>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let permits = Arc::new(Semaphore::new(1));
+        let handler = tokio::spawn(handle_client(server, Peer::Unix, chat_manager.clone(), tx.clone(), permits));
+        (client, handler)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_client_that_never_names_itself_times_out() {
+        let chat_manager = Arc::new(manager(Config::default(), Arc::new(ManualClock::new())));
+        let (tx, mut rx) = broadcast::channel(16);
+        let (mut client, handler) = connect(&chat_manager, &tx);
+        let started = tokio::time::Instant::now();
+        handler.await.unwrap();
+        assert_eq!(started.elapsed(), Config::default().name_timeout);
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        assert!(out.ends_with("Timed out waiting for a name.\n"));
+        assert!(chat_manager.users.is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn closing_at_the_name_prompt_joins_nobody() {
+        let chat_manager = Arc::new(manager(Config::default(), Arc::new(ManualClock::new())));
+        let (tx, mut rx) = broadcast::channel(16);
+        let (client, handler) = connect(&chat_manager, &tx);
+        drop(client);
+        handler.await.unwrap();
+        assert!(chat_manager.users.is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+
+    fn parse_line(line: &str) -> (Action, String) {
+        let (command, args) = split_command(line);
+        (Action::parse(&command, args), args.to_string())
+    }
+
+    #[test]
+    fn commands_ignore_case_but_arguments_keep_it() {
+        let (action, args) = parse_line("/SEARCH foo");
+        assert!(action == Action::Search(SearchField::Content));
+        assert_eq!(args, "foo");
+        let (action, args) = parse_line("/User Alice");
+        assert!(action == Action::Search(SearchField::Sender));
+        assert_eq!(args, "Alice");
+    }
+
+    #[test]
+    fn cached_searches_see_new_and_retracted_messages() {
+        let chat_manager = manager(Config::default(), Arc::new(ManualClock::new()));
+        let (tx, _rx) = broadcast::channel(16);
+        let user = chat_manager.register_user(ConnectionId(1), Peer::Unix, "alice".to_string()).unwrap();
+        chat_manager.post(chat(&chat_manager, "alice", "hello", None), &tx);
+        assert_eq!(chat_manager.cached_search(SearchField::Content, "hello").len(), 1);
+        // Repeating the search is served from the cache
+        assert_eq!(chat_manager.cached_search(SearchField::Content, "hello").len(), 1);
+        let mine = Message { author: Some(user.id.clone()), ..chat(&chat_manager, "alice", "hello again", None) };
+        chat_manager.post(mine, &tx);
+        assert_eq!(chat_manager.cached_search(SearchField::Content, "hello").len(), 2);
+        chat_manager.undo(&user, &tx).unwrap();
+        assert_eq!(chat_manager.cached_search(SearchField::Content, "hello").len(), 1);
+    }
+
+    #[test]
+    fn undo_only_reaches_back_two_minutes() {
+        let clock = Arc::new(ManualClock::new());
+        let chat_manager = manager(Config::default(), clock.clone());
+        let (tx, _rx) = broadcast::channel(16);
+        let user = chat_manager.register_user(ConnectionId(1), Peer::Unix, "alice".to_string()).unwrap();
+        let mine = |content: &str| Message { author: Some(user.id.clone()), ..chat(&chat_manager, "alice", content, None) };
+        chat_manager.post(mine("first"), &tx);
+        clock.advance(UNDO_WINDOW);
+        assert_eq!(chat_manager.undo(&user, &tx).unwrap().content, "first");
+        chat_manager.post(mine("second"), &tx);
+        clock.advance(UNDO_WINDOW + Duration::from_secs(1));
+        assert!(chat_manager.undo(&user, &tx).is_none());
+    }
+
+    #[test]
+    fn summary_counts_only_the_window() {
+        let clock = Arc::new(ManualClock::new());
+        let chat_manager = manager(Config::default(), clock.clone());
+        let (tx, _rx) = broadcast::channel(16);
+        chat_manager.post(chat(&chat_manager, "alice", "early", None), &tx);
+        clock.advance(Duration::from_secs(11 * 60));
+        chat_manager.post(chat(&chat_manager, "bob", "late", None), &tx);
+        let summary = chat_manager.summary(10).join("\n");
+        assert!(summary.contains("bob"));
+        assert!(!summary.contains("alice"));
+    }
+
+    // Run `/search <args>` over one message from alice about bob and one from bob
+    fn search(args: &str) -> Vec<String> {
+        let chat_manager = manager(Config::default(), Arc::new(ManualClock::new()));
+        let (tx, _rx) = broadcast::channel(16);
+        chat_manager.post(chat(&chat_manager, "alice", "ask bob", None), &tx);
+        chat_manager.post(chat(&chat_manager, "bob", "hi all", None), &tx);
+        let (field, order, query) = parse_search_args(args, SearchField::Content).unwrap();
+        chat_manager.search_messages(query, field, order).unwrap()
+    }
+
+    #[test]
+    fn search_in_content_is_the_default() {
+        let found = search("bob");
+        assert_eq!(found.len(), 1);
+        assert!(found[0].ends_with("alice: ask bob"));
+        assert_eq!(search("--in content bob"), found);
+    }
+
+    #[test]
+    fn search_in_sender_matches_names() {
+        let found = search("--in sender bob");
+        assert_eq!(found.len(), 1);
+        assert!(found[0].ends_with("bob: hi all"));
+    }
+
+    #[test]
+    fn search_in_both_matches_either() {
+        assert_eq!(search("--in both bob").len(), 2);
+        assert!(parse_search_args("--in rooms bob", SearchField::Content).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_stalled_reader_is_cut_off_instead_of_blocking() {
+        // The client never reads, so its socket is full after 64 bytes
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut writer = ClientWriter::spawn(server, false);
+        let line = "x".repeat(1023);
+        for _ in 0..MAX_QUEUED_OUTPUT / 1024 {
+            writer.line(&line);
+            writer.flush();
+            tokio::task::yield_now().await;
+        }
+        assert!(!writer.is_closed());
+        writer.line(&line);
+        assert!(writer.is_closed());
+        // Dropping the cut-off writer lets go of the socket straight away
+        drop(writer);
+        tokio::task::yield_now().await;
+        assert!(client.write_all(b"x").await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_stalled_write_times_out_and_frees_the_socket() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut writer = ClientWriter::spawn(server, false);
+        writer.line(&"x".repeat(1023));
+        writer.flush();
+        tokio::time::sleep(WRITE_TIMEOUT + Duration::from_secs(1)).await;
+        assert!(writer.is_closed());
+        assert!(client.write_all(b"x").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn metrics_count_every_event_even_when_the_logger_falls_behind() {
+        let bus = EventBus::new();
+        let mut logger = bus.subscribe();
+        let counted = bus.subscribe_to_every();
+        // Well past the broadcast buffer, so the logger is bound to skip some
+        for _ in 0..5000 {
+            bus.emit(Event::Message { sender: "alice".to_string(), source: Source::Tcp });
+        }
+        drop(bus);
+        let metrics = Arc::new(Metrics::default());
+        events::record_metrics(counted, metrics.clone()).await;
+        assert_eq!(metrics.messages.load(Ordering::Relaxed), 5000);
+        assert!(matches!(logger.try_recv(), Err(broadcast::error::TryRecvError::Lagged(_))));
+    }
+
+    #[test]
+    fn a_double_slash_sends_the_line_as_chat() {
+        let (action, _) = parse_line("//search is handy");
+        assert!(action == Action::Chat);
+        let (action, _) = parse_line("/whoami now");
+        assert!(action == Action::Unknown);
+        assert_eq!(unescape_chat("//search is handy"), "/search is handy");
+        // The escape doesn't change real commands or plain chat
+        let (action, _) = parse_line("/search is handy");
+        assert!(action == Action::Search(SearchField::Content));
+        assert_eq!(unescape_chat("hi /search"), "hi /search");
+    }
+
+    #[test]
+    fn emoji_count_as_one_character_each() {
+        let max = Config::default().max_message_len;
+        let at_limit = "😀".repeat(max);
+        assert_eq!(truncate_chars(&at_limit, max), at_limit);
+        let over = "😀".repeat(max + 1);
+        let kept = truncate_chars(&over, max);
+        assert_eq!(kept.chars().count(), max);
+        assert_eq!(kept.len(), 4 * max);
+        assert!(kept.chars().all(|c| c == '😀'));
+    }
+
+    #[test]
+    fn concurrent_posts_broadcast_in_stored_order() {
+        const SENDERS: usize = 8;
+        const EACH: usize = 200;
+        let chat_manager = manager(Config::default(), Arc::new(ManualClock::new()));
+        let (tx, mut rx) = broadcast::channel(SENDERS * EACH);
+        std::thread::scope(|scope| {
+            for sender in 0..SENDERS {
+                let (chat_manager, tx) = (&chat_manager, &tx);
+                scope.spawn(move || {
+                    for n in 0..EACH {
+                        let name = format!("user{}", sender);
+                        chat_manager.post(chat(chat_manager, &name, &n.to_string(), None), tx);
+                    }
+                });
+            }
+        });
+        let broadcast: Vec<String> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(broadcast.len(), SENDERS * EACH);
+        assert_eq!(broadcast, chat_manager.recent(SENDERS * EACH));
+    }
+
+    #[test]
+    fn names_reject_spaces_slashes_and_at_signs() {
+        for name in ["hello world", "/admin", "al/ice", "@alice", "alice@home", ""] {
+            assert!(check_name(name).is_err(), "{:?} was accepted", name);
+        }
+        assert!(check_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+        for name in ["alice", "Bob_2", "jean-luc", "zoë", &"a".repeat(MAX_NAME_LEN)] {
+            assert!(check_name(name).is_ok(), "{:?} was refused", name);
+        }
+    }
+
+    #[test]
+    fn racing_registrations_for_one_name_admit_one() {
+        for _ in 0..100 {
+            let chat_manager = manager(Config::default(), Arc::new(ManualClock::new()));
+            let start = std::sync::Barrier::new(2);
+            let registered = std::thread::scope(|scope| {
+                let racers: Vec<_> = (1..=2).map(|n| {
+                    let (chat_manager, start) = (&chat_manager, &start);
+                    scope.spawn(move || {
+                        start.wait();
+                        chat_manager.register_user(ConnectionId(n), Peer::Unix, "alice".to_string()).is_ok()
+                    })
+                }).collect();
+                racers.into_iter().map(|racer| racer.join().unwrap()).filter(|won| *won).count()
+            });
+            assert_eq!(registered, 1);
+            assert_eq!(chat_manager.users.len(), 1);
+        }
+    }
+
+    #[test]
+    fn a_custom_template_lays_out_lines() {
+        let template = Template::parse("<{name}> {content} {{at {ts}}}").unwrap();
+        let msg = Message {
+            timestamp: "2026-01-02 03:04:05".to_string(),
+            ..chat(&manager(Config::default(), Arc::new(ManualClock::new())), "alice", "hi", None)
+        };
+        assert_eq!(msg.format(&template), "<alice> hi {at 2026-01-02 03:04:05}");
+        assert_eq!(msg.format(&Template::default()), "[2026-01-02 03:04:05] alice: hi");
+    }
+
+    #[test]
+    fn invalid_templates_are_refused() {
+        for template in ["{who}: {content}", "{room} {content}", "{name} said", "{name}: {content"] {
+            assert!(Template::parse(template).is_err(), "{:?} was accepted", template);
+        }
+    }
+
+    #[test]
+    fn telnet_negotiation_is_stripped_from_input() {
+        // IAC WILL ECHO, then a subnegotiation, around the text
+        let mut line = vec![TELNET_IAC, TELNET_WILL, 1];
+        line.extend_from_slice(b"hi");
+        line.extend_from_slice(&[TELNET_IAC, TELNET_SB, 24, 0, b'x', TELNET_IAC, TELNET_SE]);
+        line.extend_from_slice(b" there\r\n");
+        assert_eq!(decode_line(&line), "hi there");
+        // An escaped 0xFF is data
+        assert_eq!(strip_telnet_commands(&[b'a', TELNET_IAC, TELNET_IAC, b'b']), vec![b'a', TELNET_IAC, b'b']);
+    }
+
+    #[test]
+    fn bot_waits_out_its_cooldown() {
+        let clock = Arc::new(ManualClock::new());
+        let config = Config { bot_triggers: vec![("hello".to_string(), "Hi!".to_string())], ..Config::default() };
+        let chat_manager = manager(config, clock.clone());
+        assert_eq!(chat_manager.bot_reply("Hello there").unwrap().content, "Hi!");
+        clock.advance(BOT_COOLDOWN - Duration::from_secs(1));
+        assert!(chat_manager.bot_reply("hello again").is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(chat_manager.bot_reply("hello again").is_some());
+    }
+}
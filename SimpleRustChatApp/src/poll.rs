@@ -0,0 +1,167 @@
+// Quick votes: `/poll` opens one for everyone, `/vote` fills it in, `/poll-results` reads the tally.
+// Votes are keyed by user name, so each user has one vote per poll and voting again changes it.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const POLL_USAGE: &str = "Usage: /poll [--for <minutes>] \"Question?\" <option> <option> [more options]";
+pub const VOTE_USAGE: &str = "Usage: /vote <poll-id> <option number or text>";
+// Polls kept for voting and results; the oldest closed poll makes room for a new one
+const MAX_POLLS: usize = 10;
+const MAX_OPTIONS: usize = 10;
+const MAX_POLL_MINUTES: u64 = 7 * 24 * 60;
+
+pub struct Poll {
+    pub id: u64,
+    question: String,
+    options: Vec<String>,
+    // Voter name to the index of the option they picked
+    votes: HashMap<String, usize>,
+    // None keeps the poll open until it is pushed out
+    closes_at: Option<Instant>,
+}
+
+impl Poll {
+    fn is_open(&self, now: Instant) -> bool {
+        self.closes_at.is_none_or(|closes_at| now < closes_at)
+    }
+
+    // Broadcast text announcing a new poll and how to vote in it
+    pub fn announcement(&self, creator: &str, now: Instant) -> String {
+        let mut text = format!("\n*** {} started poll #{}: {} ***\n", creator, self.id, self.question);
+        for (number, option) in self.options.iter().enumerate() {
+            text.push_str(&format!("  {}. {}\n", number + 1, option));
+        }
+        if let Some(minutes) = self.minutes_left(now) {
+            text.push_str(&format!("Closes in {} min. ", minutes));
+        }
+        text.push_str(&format!("Vote with /vote {} <number or option>\n", self.id));
+        text
+    }
+
+    // Whole minutes until close, rounded up; None for polls without an expiry
+    fn minutes_left(&self, now: Instant) -> Option<u64> {
+        let left = self.closes_at?.saturating_duration_since(now);
+        Some(left.as_secs().div_ceil(60))
+    }
+}
+
+#[derive(Default)]
+pub struct PollBook {
+    polls: Vec<Poll>,
+    last_id: u64,
+}
+
+impl PollBook {
+    pub fn open(&mut self, question: String, options: Vec<String>, duration: Option<Duration>, now: Instant) -> Result<&Poll, &'static str> {
+        if self.polls.len() >= MAX_POLLS {
+            let closed = self.polls.iter().position(|poll| !poll.is_open(now))
+                .ok_or("Too many open polls; wait for one to close.")?;
+            self.polls.remove(closed);
+        }
+        self.last_id += 1;
+        self.polls.push(Poll {
+            id: self.last_id,
+            question,
+            options,
+            votes: HashMap::new(),
+            closes_at: duration.map(|duration| now + duration),
+        });
+        Ok(self.polls.last().unwrap())
+    }
+
+    // `choice` is an option number or the option text, ignoring case
+    pub fn vote(&mut self, id: u64, voter: &str, choice: &str, now: Instant) -> Result<String, String> {
+        let poll = self.polls.iter_mut().find(|poll| poll.id == id)
+            .ok_or_else(|| format!("No poll #{}.", id))?;
+        if !poll.is_open(now) {
+            return Err(format!("Poll #{} is closed.", id));
+        }
+        let index = match choice.parse::<usize>() {
+            Ok(number) if (1..=poll.options.len()).contains(&number) => number - 1,
+            Ok(_) => return Err(format!("Poll #{} has options 1 to {}.", id, poll.options.len())),
+            Err(_) => poll.options.iter().position(|option| option.eq_ignore_ascii_case(choice))
+                .ok_or_else(|| format!("Poll #{} has no option '{}'.", id, choice))?,
+        };
+        let verb = match poll.votes.insert(voter.to_string(), index) {
+            Some(_) => "changed to",
+            None => "recorded for",
+        };
+        Ok(format!("Vote {} '{}' in poll #{}.", verb, poll.options[index], id))
+    }
+
+    pub fn results(&self, id: u64, now: Instant) -> Option<Vec<String>> {
+        let poll = self.polls.iter().find(|poll| poll.id == id)?;
+        let state = match poll.minutes_left(now) {
+            _ if !poll.is_open(now) => "closed".to_string(),
+            Some(minutes) => format!("open, closes in {} min", minutes),
+            None => "open".to_string(),
+        };
+        let mut lines = vec![format!("Poll #{}: {} ({})", poll.id, poll.question, state)];
+        for (index, option) in poll.options.iter().enumerate() {
+            let count = poll.votes.values().filter(|vote| **vote == index).count();
+            let noun = if count == 1 { "vote" } else { "votes" };
+            lines.push(format!("  {}. {} - {} {}", index + 1, option, count, noun));
+        }
+        lines.push(format!("{} voted", poll.votes.len()));
+        Some(lines)
+    }
+}
+
+// `[--for <minutes>] "Question?" opt1 opt2 ...`; quote any question or option with spaces
+pub fn parse_poll_args(args: &str) -> Result<(String, Vec<String>, Option<Duration>), &'static str> {
+    let mut words = split_quoted(args).ok_or("Unclosed quote in poll.")?;
+    let mut duration = None;
+    if words.first().is_some_and(|word| word == "--for") {
+        let minutes = words.get(1).and_then(|m| m.parse::<u64>().ok())
+            .filter(|m| (1..=MAX_POLL_MINUTES).contains(m))
+            .ok_or("Poll length must be 1 to 10080 minutes.")?;
+        duration = Some(Duration::from_secs(minutes * 60));
+        words.drain(..2);
+    }
+    if words.len() < 3 || words.iter().any(String::is_empty) {
+        return Err(POLL_USAGE);
+    }
+    let question = words.remove(0);
+    if words.len() > MAX_OPTIONS {
+        return Err("A poll can have at most 10 options.");
+    }
+    for (i, option) in words.iter().enumerate() {
+        if words[..i].iter().any(|earlier| earlier.eq_ignore_ascii_case(option)) {
+            return Err("Poll options must be different.");
+        }
+    }
+    Ok((question, words, duration))
+}
+
+// Split on whitespace, keeping "quoted phrases" together; None if a quote is left open
+fn split_quoted(args: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let (mut in_word, mut in_quotes) = (false, false);
+    for c in args.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_quotes {
+        return None;
+    }
+    if in_word {
+        words.push(word);
+    }
+    Some(words)
+}
@@ -0,0 +1,433 @@
+// Where chat history lives. ChatManager only talks to a MessageStore, so a
+// persistent backend can replace the in-memory one without touching callers.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use rusqlite::{params, Connection, Row};
+
+use crate::{Message, SearchField, Source};
+
+// What a search looks for; an empty `text` matches every message
+#[derive(Clone, Copy)]
+pub struct SearchQuery<'a> {
+    pub text: &'a str,
+    pub field: SearchField,
+    pub ignore_case: bool,
+    // Only messages sent at or after this time, in Unix seconds
+    pub since: Option<i64>,
+}
+
+impl<'a> SearchQuery<'a> {
+    pub fn new(text: &'a str, field: SearchField) -> Self {
+        SearchQuery { text, field, ignore_case: false, since: None }
+    }
+
+    pub fn matches(&self, msg: &Message) -> bool {
+        if self.since.is_some_and(|since| msg.sent_at < since) {
+            return false;
+        }
+        let contains = |haystack: &str| {
+            if self.ignore_case {
+                haystack.to_lowercase().contains(&self.text.to_lowercase())
+            } else {
+                haystack.contains(self.text)
+            }
+        };
+        match self.field {
+            SearchField::Content => contains(&msg.content),
+            SearchField::Sender => contains(&msg.sender_id),
+            SearchField::Both => contains(&msg.content) || contains(&msg.sender_id),
+        }
+    }
+}
+
+pub trait MessageStore: Send + Sync {
+    fn store(&self, msg: Message);
+    fn len(&self) -> usize;
+    // Newest first, skipping the `offset` newest
+    fn recent(&self, offset: usize, limit: usize) -> Vec<Message>;
+    // Oldest first
+    fn search(&self, query: &SearchQuery) -> Vec<Message>;
+    // Messages per sender, to seed the counters from history kept across restarts
+    fn sender_counts(&self) -> HashMap<String, u64>;
+    // Delete messages that expire at or before `now`, in Unix seconds,
+    // returning how many went per sender
+    fn purge_expired(&self, now: i64) -> HashMap<String, u64>;
+    // Delete and return the author's latest message, if it was sent at or after `since`, in Unix seconds
+    fn remove_latest(&self, author: &str, since: i64) -> Option<Message>;
+    // Content matches from a full-text index, best first if `ranked` and oldest
+    // first otherwise; None when the store has no index and callers should scan
+    fn full_text_search(&self, _query: &str, _ranked: bool) -> Option<Vec<Message>> {
+        None
+    }
+    // Goes up each time history changes, so a saved read can tell it is stale
+    fn generation(&self) -> u64;
+}
+
+// The default: history kept in memory and lost on restart
+#[derive(Default)]
+pub struct MemoryStore {
+    messages: Mutex<Vec<Message>>,
+    generation: AtomicU64,
+}
+
+impl MessageStore for MemoryStore {
+    fn store(&self, msg: Message) {
+        self.messages.lock().unwrap().push(msg);
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    fn recent(&self, offset: usize, limit: usize) -> Vec<Message> {
+        self.messages.lock().unwrap().iter().rev().skip(offset).take(limit).cloned().collect()
+    }
+
+    fn search(&self, query: &SearchQuery) -> Vec<Message> {
+        self.messages.lock().unwrap().iter().filter(|msg| query.matches(msg)).cloned().collect()
+    }
+
+    fn sender_counts(&self) -> HashMap<String, u64> {
+        let mut counts = HashMap::new();
+        for msg in self.messages.lock().unwrap().iter() {
+            *counts.entry(msg.sender_id.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    fn purge_expired(&self, now: i64) -> HashMap<String, u64> {
+        let mut removed = HashMap::new();
+        self.messages.lock().unwrap().retain(|msg| {
+            let expired = msg.expires_at.is_some_and(|at| at <= now);
+            if expired {
+                *removed.entry(msg.sender_id.clone()).or_insert(0) += 1;
+            }
+            !expired
+        });
+        if !removed.is_empty() {
+            self.generation.fetch_add(1, Ordering::Release);
+        }
+        removed
+    }
+
+    fn remove_latest(&self, author: &str, since: i64) -> Option<Message> {
+        let mut messages = self.messages.lock().unwrap();
+        let latest = messages.iter().rposition(|msg| msg.author.as_deref() == Some(author))?;
+        if messages[latest].sent_at < since {
+            return None;
+        }
+        self.generation.fetch_add(1, Ordering::Release);
+        Some(messages.remove(latest))
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
+// Schema changes, applied in order; PRAGMA user_version records how many have run
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE messages (
+        id INTEGER PRIMARY KEY,
+        sender TEXT NOT NULL,
+        content TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        source TEXT NOT NULL
+    );
+    CREATE INDEX messages_timestamp ON messages (timestamp);
+    CREATE INDEX messages_sender ON messages (sender);",
+    // Full-text index over message content, kept in step with the table by triggers
+    "CREATE VIRTUAL TABLE messages_fts USING fts5 (content, content = 'messages', content_rowid = 'id');
+    CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+        INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+    END;
+    CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+        INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+    END;
+    INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');",
+    // Expiry from the sender's /retention; older messages never expire
+    "ALTER TABLE messages ADD COLUMN expires_at TEXT;
+    CREATE INDEX messages_expires_at ON messages (expires_at) WHERE expires_at IS NOT NULL;",
+    // The registration that sent each message, for /undo; older messages have none
+    "ALTER TABLE messages ADD COLUMN author TEXT;
+    CREATE INDEX messages_author ON messages (author) WHERE author IS NOT NULL;",
+    // Expiry as Unix seconds instead of local time, which repeats an hour when daylight saving ends
+    "DROP INDEX messages_expires_at;
+    ALTER TABLE messages RENAME COLUMN expires_at TO expires_at_local;
+    ALTER TABLE messages ADD COLUMN expires_at INTEGER;
+    UPDATE messages SET expires_at = CAST(strftime('%s', expires_at_local, 'utc') AS INTEGER)
+        WHERE expires_at_local IS NOT NULL;
+    ALTER TABLE messages DROP COLUMN expires_at_local;
+    CREATE INDEX messages_expires_at ON messages (expires_at) WHERE expires_at IS NOT NULL;",
+    // When each message was sent in Unix seconds, filled in from the local timestamps
+    "ALTER TABLE messages ADD COLUMN sent_at INTEGER NOT NULL DEFAULT 0;
+    UPDATE messages SET sent_at = CAST(strftime('%s', timestamp, 'utc') AS INTEGER);
+    CREATE INDEX messages_sent_at ON messages (sent_at);",
+];
+
+// History in a SQLite file, so it survives restarts and isn't all held in
+// memory. Changes go to a writer thread with its own connection, so storing a
+// message never waits on the disk; reads use a second connection and see a
+// change once the writer has committed it.
+pub struct SqliteStore {
+    reader: Mutex<Connection>,
+    // Both Some until drop, which closes the queue and waits for it to drain
+    writes: Option<mpsc::Sender<Write>>,
+    writer: Option<thread::JoinHandle<()>>,
+    generation: Arc<AtomicU64>,
+}
+
+// Work for the writer thread; `done` carries the result back to a caller that waits for it
+enum Write {
+    Insert(Message),
+    PurgeExpired { now: i64, done: mpsc::Sender<HashMap<String, u64>> },
+    RemoveLatest { author: String, since: i64, done: mpsc::Sender<Option<Message>> },
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let error = |e: rusqlite::Error| format!("cannot open database {}: {}", path, e);
+        let mut db = Connection::open(path).map_err(error)?;
+        // WAL lets the reader run while the writer commits, and in WAL mode
+        // NORMAL only syncs at checkpoints; a crash can lose the last few
+        // messages but never corrupts the file
+        db.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0)).map_err(error)?;
+        db.pragma_update(None, "synchronous", "NORMAL").map_err(error)?;
+        migrate(&mut db).map_err(error)?;
+        let reader = Connection::open(path).map_err(error)?;
+        let generation = Arc::new(AtomicU64::new(0));
+        let (writes, queue) = mpsc::channel();
+        let writer = {
+            let generation = generation.clone();
+            thread::spawn(move || write_queued(db, queue, generation))
+        };
+        Ok(SqliteStore { reader: Mutex::new(reader), writes: Some(writes), writer: Some(writer), generation })
+    }
+
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> rusqlite::Result<Vec<Message>> {
+        let db = self.reader.lock().unwrap();
+        let mut statement = db.prepare_cached(sql)?;
+        let rows = statement.query_map(params, message_from_row)?;
+        rows.collect()
+    }
+
+    fn send(&self, write: Write) {
+        // The writer only stops once the queue is closed in drop
+        self.writes.as_ref().unwrap().send(write).unwrap();
+    }
+}
+
+impl Drop for SqliteStore {
+    fn drop(&mut self) {
+        drop(self.writes.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn message_from_row(row: &Row) -> rusqlite::Result<Message> {
+    let source: String = row.get(3)?;
+    Ok(Message {
+        sender_id: row.get(0)?,
+        content: row.get(1)?,
+        timestamp: row.get(2)?,
+        sent_at: row.get(6)?,
+        source: source_from_key(&source),
+        author: row.get(4)?,
+        expires_at: row.get(5)?,
+    })
+}
+
+// The writer thread: apply each change in the order it was queued
+fn write_queued(db: Connection, queue: mpsc::Receiver<Write>, generation: Arc<AtomicU64>) {
+    for write in queue {
+        match write {
+            Write::Insert(msg) => {
+                let inserted = db.execute(
+                    "INSERT INTO messages (sender, content, timestamp, source, author, expires_at, sent_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![msg.sender_id, msg.content, msg.timestamp, source_key(msg.source), msg.author, msg.expires_at, msg.sent_at],
+                );
+                if let Err(e) = inserted {
+                    eprintln!("error: cannot save message: {}", e);
+                }
+            }
+            // The delete trigger takes the messages out of the full-text index too
+            Write::PurgeExpired { now, done } => {
+                let deleted = db.prepare_cached("DELETE FROM messages WHERE expires_at <= ?1 RETURNING sender")
+                    .and_then(|mut statement| {
+                        let mut removed = HashMap::new();
+                        for sender in statement.query_map(params![now], |row| row.get::<_, String>(0))? {
+                            *removed.entry(sender?).or_insert(0) += 1;
+                        }
+                        Ok(removed)
+                    });
+                let _ = done.send(deleted.unwrap_or_else(|e| {
+                    eprintln!("error: cannot delete expired messages: {}", e);
+                    HashMap::new()
+                }));
+            }
+            Write::RemoveLatest { author, since, done } => {
+                let sql = "DELETE FROM messages
+                           WHERE id = (SELECT max(id) FROM messages WHERE author = ?1) AND sent_at >= ?2
+                           RETURNING sender, content, timestamp, source, author, expires_at, sent_at";
+                let removed = db.prepare_cached(sql)
+                    .and_then(|mut statement| statement.query_map(params![author, since], message_from_row)?.next().transpose());
+                let _ = done.send(removed.unwrap_or_else(|e| {
+                    eprintln!("error: cannot delete message: {}", e);
+                    None
+                }));
+            }
+        }
+        generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+fn migrate(db: &mut Connection) -> rusqlite::Result<()> {
+    let applied: i64 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (version, sql) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+        let tx = db.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", version as i64 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+// Failures are reported and the server keeps going on whatever it has
+impl MessageStore for SqliteStore {
+    fn store(&self, msg: Message) {
+        self.send(Write::Insert(msg));
+    }
+
+    fn len(&self) -> usize {
+        let db = self.reader.lock().unwrap();
+        match db.query_row("SELECT count(*) FROM messages", [], |row| row.get::<_, i64>(0)) {
+            Ok(count) => count as usize,
+            Err(e) => {
+                eprintln!("error: cannot count messages: {}", e);
+                0
+            }
+        }
+    }
+
+    fn recent(&self, offset: usize, limit: usize) -> Vec<Message> {
+        let sql = "SELECT sender, content, timestamp, source, author, expires_at, sent_at FROM messages ORDER BY id DESC LIMIT ?1 OFFSET ?2";
+        self.query(sql, params![limit as i64, offset as i64]).unwrap_or_else(|e| {
+            eprintln!("error: cannot read messages: {}", e);
+            Vec::new()
+        })
+    }
+
+    // Same matching as MemoryStore, except that SQLite's lower() only folds ASCII letters
+    fn search(&self, query: &SearchQuery) -> Vec<Message> {
+        let column = |name: &str| {
+            if query.ignore_case {
+                format!("instr(lower({}), lower(?1)) > 0", name)
+            } else {
+                format!("instr({}, ?1) > 0", name)
+            }
+        };
+        let matched = match query.field {
+            SearchField::Content => column("content"),
+            SearchField::Sender => column("sender"),
+            SearchField::Both => format!("({} OR {})", column("content"), column("sender")),
+        };
+        let sql = format!(
+            "SELECT sender, content, timestamp, source, author, expires_at, sent_at FROM messages
+             WHERE {} AND (?2 IS NULL OR sent_at >= ?2) ORDER BY id",
+            matched
+        );
+        self.query(&sql, params![query.text, query.since]).unwrap_or_else(|e| {
+            eprintln!("error: cannot search messages: {}", e);
+            Vec::new()
+        })
+    }
+
+    fn full_text_search(&self, query: &str, ranked: bool) -> Option<Vec<Message>> {
+        let sql = format!(
+            "SELECT m.sender, m.content, m.timestamp, m.source, m.author, m.expires_at, m.sent_at FROM messages_fts
+             JOIN messages m ON m.id = messages_fts.rowid
+             WHERE messages_fts MATCH ?1 ORDER BY {}",
+            if ranked { "messages_fts.rank" } else { "m.id" }
+        );
+        let found = self.query(&sql, params![fts_query(query)]).unwrap_or_else(|e| {
+            eprintln!("error: cannot search messages: {}", e);
+            Vec::new()
+        });
+        Some(found)
+    }
+
+    fn sender_counts(&self) -> HashMap<String, u64> {
+        let db = self.reader.lock().unwrap();
+        let counts = db.prepare("SELECT sender, count(*) FROM messages GROUP BY sender").and_then(|mut statement| {
+            statement.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?.collect()
+        });
+        counts.unwrap_or_else(|e| {
+            eprintln!("error: cannot count messages: {}", e);
+            HashMap::new()
+        })
+    }
+
+    // Queued behind any pending inserts, so it sees every message stored before it
+    fn purge_expired(&self, now: i64) -> HashMap<String, u64> {
+        let (done, result) = mpsc::channel();
+        self.send(Write::PurgeExpired { now, done });
+        result.recv().unwrap_or_default()
+    }
+
+    fn remove_latest(&self, author: &str, since: i64) -> Option<Message> {
+        let (done, result) = mpsc::channel();
+        self.send(Write::RemoveLatest { author: author.to_string(), since, done });
+        result.recv().ok().flatten()
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
+// Turn a /search query into FTS5 syntax: "quoted phrases" stay phrases, every
+// other word must appear, and a trailing * matches any word with that prefix.
+// Everything is quoted, so no input can be an FTS5 syntax error.
+fn fts_query(query: &str) -> String {
+    let mut terms = Vec::new();
+    for (i, part) in query.split('"').enumerate() {
+        if i % 2 == 1 {
+            if !part.trim().is_empty() {
+                terms.push(format!("\"{}\"", part));
+            }
+            continue;
+        }
+        for word in part.split_whitespace() {
+            match word.strip_suffix('*') {
+                Some(prefix) if !prefix.is_empty() => terms.push(format!("\"{}\"*", prefix)),
+                _ => terms.push(format!("\"{}\"", word)),
+            }
+        }
+    }
+    terms.join(" ")
+}
+
+// Stable names for the source column, independent of how Source is labelled for people
+fn source_key(source: Source) -> &'static str {
+    match source {
+        Source::Tcp => "tcp",
+        Source::Unix => "unix",
+        Source::Webhook => "webhook",
+        Source::Bot => "bot",
+    }
+}
+
+fn source_from_key(key: &str) -> Source {
+    match key {
+        "unix" => Source::Unix,
+        "webhook" => Source::Webhook,
+        "bot" => Source::Bot,
+        _ => Source::Tcp,
+    }
+}
@@ -0,0 +1,171 @@
+// Minimal HTTP endpoint that lets external services (CI, alerting) post into the chat.
+// Only `POST /` with a JSON body is understood; everything else is rejected.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+use crate::{check_name, truncate_chars, ChatManager, Message, Source};
+
+const SECRET_HEADER: &str = "x-webhook-secret";
+const MAX_BODY_BYTES: usize = 8 * 1024;
+const MAX_POSTS_PER_MINUTE: usize = 30;
+
+// Unknown fields such as `room` are ignored, since there is only one channel today
+#[derive(Deserialize)]
+struct Payload {
+    name: String,
+    content: String,
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    secret: String,
+    chat_manager: Arc<ChatManager>,
+    tx: broadcast::Sender<String>,
+) {
+    let secret = Arc::new(secret);
+    let recent_posts = Arc::new(Mutex::new(VecDeque::new()));
+
+    loop {
+        let Ok((socket, _)) = listener.accept().await else { continue };
+        let secret = secret.clone();
+        let recent_posts = recent_posts.clone();
+        let chat_manager = chat_manager.clone();
+        let tx = tx.clone();
+
+        tokio::spawn(async move {
+            let request = handle(socket, &secret, &recent_posts, &chat_manager, &tx);
+            let _ = tokio::time::timeout(Duration::from_secs(5), request).await;
+        });
+    }
+}
+
+async fn handle(
+    socket: TcpStream,
+    secret: &str,
+    recent_posts: &Mutex<VecDeque<Instant>>,
+    chat_manager: &ChatManager,
+    tx: &broadcast::Sender<String>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+    let status = match read_request(&mut reader).await? {
+        Err(status) => status,
+        Ok((headers, body)) => accept_post(&headers, &body, secret, recent_posts, chat_manager, tx),
+    };
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+    writer.write_all(response.as_bytes()).await?;
+    writer.flush().await
+}
+
+// Read the request line, headers and body; protocol errors come back as an HTTP status
+async fn read_request<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Result<(Vec<(String, String)>, Vec<u8>), &'static str>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    if method != "POST" {
+        return Ok(Err("405 Method Not Allowed"));
+    }
+    if path != "/" {
+        return Ok(Err("404 Not Found"));
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(Err("400 Bad Request"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+        if headers.len() > 64 {
+            return Ok(Err("431 Request Header Fields Too Large"));
+        }
+    }
+
+    let length = header_value(&headers, "content-length").and_then(|v| v.parse::<usize>().ok());
+    let Some(length) = length else { return Ok(Err("411 Length Required")) };
+    if length > MAX_BODY_BYTES {
+        return Ok(Err("413 Payload Too Large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Ok((headers, body)))
+}
+
+fn accept_post(
+    headers: &[(String, String)],
+    body: &[u8],
+    secret: &str,
+    recent_posts: &Mutex<VecDeque<Instant>>,
+    chat_manager: &ChatManager,
+    tx: &broadcast::Sender<String>,
+) -> &'static str {
+    let presented = header_value(headers, SECRET_HEADER).unwrap_or("");
+    if !constant_time_eq(presented.as_bytes(), secret.as_bytes()) {
+        return "401 Unauthorized";
+    }
+
+    let Ok(payload) = serde_json::from_slice::<Payload>(body) else { return "400 Bad Request" };
+    let name = payload.name.trim();
+    let content = payload.content.trim();
+    // The name follows the same rules as a client's, including the bot's
+    // reservation, and can't be someone who is online
+    if check_name(name).is_err() || !is_valid_content(content) {
+        return "422 Unprocessable Entity";
+    }
+    if chat_manager.find_user(name).is_some() {
+        return "409 Conflict";
+    }
+
+    {
+        let now = chat_manager.clock.instant();
+        let mut recent_posts = recent_posts.lock().unwrap();
+        while recent_posts.front().is_some_and(|t| now.duration_since(*t) >= Duration::from_secs(60)) {
+            recent_posts.pop_front();
+        }
+        if recent_posts.len() >= MAX_POSTS_PER_MINUTE {
+            return "429 Too Many Requests";
+        }
+        recent_posts.push_back(now);
+    }
+
+    // Long content is cut to --max-message-len, as it is for chat from clients
+    let msg = Message {
+        sender_id: name.to_string(),
+        content: truncate_chars(content, chat_manager.config.max_message_len).to_string(),
+        timestamp: chat_manager.timestamp(),
+        sent_at: chat_manager.sent_at(),
+        source: Source::Webhook,
+        author: None,
+        expires_at: None,
+    };
+    chat_manager.post(msg, tx);
+    "204 No Content"
+}
+
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+}
+
+// Non-empty and free of control characters that could forge extra chat lines
+fn is_valid_content(value: &str) -> bool {
+    !value.is_empty() && !value.chars().any(char::is_control)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}